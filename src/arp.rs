@@ -0,0 +1,268 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use random::Random;
+
+/// Order in which the held notes are played
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pattern {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    /// In the order the keys were pressed
+    Played,
+}
+
+impl Pattern {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "up" => Some(Pattern::Up),
+            "down" => Some(Pattern::Down),
+            "updown" => Some(Pattern::UpDown),
+            "random" => Some(Pattern::Random),
+            "played" => Some(Pattern::Played),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Pattern::Up => "up",
+            Pattern::Down => "down",
+            Pattern::UpDown => "updown",
+            Pattern::Random => "random",
+            Pattern::Played => "played",
+        }
+    }
+}
+
+/// Plays the held notes one after another on its own clock
+#[derive(Debug)]
+pub struct Arp {
+    pattern: Option<Pattern>,
+    bpm: u16,
+    per_beat: u8,
+    /// Length of the notes in percents of the step
+    gate: u8,
+    octaves: u8,
+    /// Notes with their velocities in the order they were pressed
+    keys: Vec<(u8, u8)>,
+    step: usize,
+    next: Option<Instant>,
+    off: Option<Instant>,
+}
+
+impl Arp {
+    pub fn new() -> Self {
+        Arp {
+            pattern: None,
+            bpm: 120,
+            per_beat: 4,
+            gate: 50,
+            octaves: 1,
+            keys: vec![],
+            step: 0,
+            next: None,
+            off: None,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.pattern.is_some()
+    }
+
+    /// `None` turns the arpeggiator off, dropping the held notes
+    pub fn set_pattern(&mut self, pattern: Option<Pattern>) {
+        if pattern.is_none() {
+            self.keys.clear();
+            self.next = None;
+        }
+        self.pattern = pattern;
+    }
+
+    pub fn set_rate(&mut self, bpm: u16, per_beat: u8) {
+        self.bpm = bpm;
+        self.per_beat = per_beat;
+    }
+
+    pub fn set_gate(&mut self, percent: u8) {
+        self.gate = percent;
+    }
+
+    pub fn set_octaves(&mut self, octaves: u8) {
+        self.octaves = octaves;
+    }
+
+    /// A key pressed, the first one starts the clock
+    pub fn press(&mut self, note: u8, velocity: u8) {
+        if self.keys.is_empty() {
+            self.step = 0;
+            self.next = Some(Instant::now());
+        }
+        self.keys.push((note, velocity));
+    }
+
+    pub fn release(&mut self, note: u8) {
+        if let Some(i) = self.keys.iter().position(|&(n, _)| n == note) {
+            self.keys.remove(i);
+        }
+        if self.keys.is_empty() {
+            self.next = None;
+        }
+    }
+
+    pub fn holds(&self, note: u8) -> bool {
+        self.keys.iter().any(|&(n, _)| n == note)
+    }
+
+    /// When the arpeggiator has to be ticked next
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.next, self.off) {
+            (Some(next), Some(off)) => Some(next.min(off)),
+            (next, off) => next.or(off),
+        }
+    }
+
+    /// Advances the clock to `now`. Returns whether the sounding note ends and
+    /// the note and velocity that start, picked with `random` in the random pattern.
+    pub fn tick(&mut self, now: Instant, random: &mut Random) -> (bool, Option<(u8, u8)>) {
+        let mut end = match self.off {
+            Some(off) if off <= now => {
+                self.off = None;
+                true
+            },
+            _ => false,
+        };
+
+        let next = match self.next {
+            Some(next) if next <= now => next,
+            _ => return (end, None),
+        };
+
+        let step = self.interval();
+        // After a stall the clock starts over instead of playing the missed steps at once
+        self.next = Some(if now - next > step { now + step } else { next + step });
+        if self.off.take().is_some() { end = true }
+        self.off = Some(next + step * self.gate as u32 / 100);
+
+        let notes = self.sequence();
+        let i = match self.pattern {
+            Some(Pattern::Down) => notes.len() - 1 - self.step % notes.len(),
+            Some(Pattern::UpDown) if notes.len() > 1 => {
+                let period = 2 * notes.len() - 2;
+                let i = self.step % period;
+                if i < notes.len() { i } else { period - i }
+            },
+            Some(Pattern::Random) => random.next_u32() as usize % notes.len(),
+            _ => self.step % notes.len(),
+        };
+        self.step += 1;
+
+        (end, notes.get(i).cloned())
+    }
+
+    fn interval(&self) -> Duration {
+        let us = 60000000 / (self.bpm as u64 * self.per_beat as u64);
+        Duration::new(us / 1000000, (us % 1000000) as u32 * 1000)
+    }
+
+    /// Held notes repeated over the octaves in the order of the pattern
+    fn sequence(&self) -> Vec<(u8, u8)> {
+        let mut keys = self.keys.clone();
+        if self.pattern != Some(Pattern::Played) {
+            keys.sort();
+            keys.dedup_by_key(|k| k.0);
+        }
+
+        (0..self.octaves as u16).flat_map(|o| keys.iter().map(move |&(n, v)| (n as u16 + 12 * o, v)))
+            .filter(|&(n, _)| n < 128).map(|(n, v)| (n as u8, v)).collect()
+    }
+}
+
+impl fmt::Display for Arp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pattern {
+            Some(pattern) => write!(f, "arp {} {}×{} {}%{}", pattern.name(), self.bpm, self.per_beat,
+                self.gate, if self.octaves > 1 { format!(" {} octaves", self.octaves) } else { String::new() }),
+            None => write!(f, "arp off"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The next notes started, ticking the arpeggiator whenever it's due
+    fn play(arp: &mut Arp, count: usize) -> Vec<u8> {
+        let mut random = Random::new();
+        let mut notes = vec![];
+        while notes.len() < count {
+            let now = arp.deadline().unwrap();
+            if let (_, Some((note, _))) = arp.tick(now, &mut random) { notes.push(note) }
+        }
+        notes
+    }
+
+    fn arp(pattern: Pattern, keys: &[u8]) -> Arp {
+        let mut arp = Arp::new();
+        arp.set_pattern(Some(pattern));
+        for &k in keys { arp.press(k, 100) }
+        arp
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(play(&mut arp(Pattern::Up, &[64, 60, 67]), 4), vec![60, 64, 67, 60]);
+        assert_eq!(play(&mut arp(Pattern::Down, &[64, 60, 67]), 4), vec![67, 64, 60, 67]);
+        assert_eq!(play(&mut arp(Pattern::UpDown, &[64, 60, 67]), 6), vec![60, 64, 67, 64, 60, 64]);
+        assert_eq!(play(&mut arp(Pattern::UpDown, &[60]), 2), vec![60, 60]);
+        assert_eq!(play(&mut arp(Pattern::Played, &[64, 60, 64]), 3), vec![64, 60, 64]);
+
+        let random = play(&mut arp(Pattern::Random, &[64, 60, 67]), 20);
+        assert!(random.iter().all(|n| [60, 64, 67].contains(n)));
+    }
+
+    #[test]
+    fn octaves() {
+        let mut up = arp(Pattern::Up, &[60, 110]);
+        up.set_octaves(3);
+        // Notes above 127 are left out
+        assert_eq!(play(&mut up, 6), vec![60, 110, 72, 122, 84, 60]);
+    }
+
+    #[test]
+    fn steps_and_gate() {
+        let mut arp = arp(Pattern::Up, &[60, 64]);
+        arp.set_rate(60, 2);
+        arp.set_gate(25);
+        let mut random = Random::new();
+
+        let start = arp.deadline().unwrap();
+        assert_eq!(arp.tick(start, &mut random), (false, Some((60, 100))));
+        assert_eq!(arp.deadline(), Some(start + Duration::from_millis(125)));
+        assert_eq!(arp.tick(start + Duration::from_millis(125), &mut random), (true, None));
+        assert_eq!(arp.deadline(), Some(start + Duration::from_millis(500)));
+        assert_eq!(arp.tick(start + Duration::from_millis(500), &mut random), (false, Some((64, 100))));
+    }
+
+    #[test]
+    fn releases() {
+        let mut arp = arp(Pattern::Up, &[60, 64]);
+        play(&mut arp, 1);
+
+        arp.release(60);
+        assert!(!arp.holds(60) && arp.holds(64));
+        arp.release(64);
+        // Only the end of the sounding note is left
+        let off = arp.deadline().unwrap();
+        assert_eq!(arp.tick(off, &mut Random::new()), (true, None));
+        assert_eq!(arp.deadline(), None);
+
+        arp.set_pattern(None);
+        arp.press(60, 100);
+        assert!(!arp.is_on());
+        assert_eq!(arp.to_string(), "arp off");
+    }
+}
@@ -1,43 +1,389 @@
+#[cfg(feature = "gui")]
 use sfml::graphics::{RectangleShape, Transformable};
+#[cfg(feature = "gui")]
 use sfml::system::Vector2f;
+#[cfg(feature = "gui")]
 use sfml::graphics::{RenderTarget, Color, Text, Font, Drawable, RenderStates, Shape};
-use portmidi::{PortMidi, DeviceInfo};
 
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use velocity::Curve;
+use arp::Pattern;
+use error::{Error, Result};
+use midi::{Device, Devices};
+use gm::Instruments;
+use ui::Spelling;
+
+#[cfg(feature = "gui")]
 const LINES: f32 = 4.0;
 const CMDS: &'static [&'static str] = &[
     "dev",
     "chan",
     "prog",
+    "layout",
+    "cc",
+    "vel",
+    "sustain",
+    "thru",
+    "rec",
+    "stop",
+    "save",
+    "session",
+    "play",
+    "pause",
+    "seek",
+    "tempo",
+    "wait",
+    "transpose",
+    "labels",
+    "tuning",
+    "mpe",
+    "bendrange",
+    "chord",
+    "arp",
+];
+
+/// How a command is written, shown when its arguments are wrong
+pub fn usage(cmd: &str) -> &'static str {
+    match cmd {
+        "dev" => "dev <name|id>",
+        "chan" => "chan <0-15>",
+        "prog" => "prog <program>/<bank>|<name>|names <file.ins> [instrument]|names gm",
+        "layout" => "layout <file>|default",
+        "cc" => "cc <controller> <value>",
+        "vel" => "vel <1-127>|fixed|human <range>|accent <top> <row 2> <row 3> <bottom>",
+        "sustain" => "sustain local|cc",
+        "thru" => "thru [name|id]",
+        "save" => "save [file] [0|1]",
+        "session" => "session save|load <file>",
+        "play" => "play [file]",
+        "seek" => "seek [minutes:]seconds",
+        "tempo" => "tempo <10-400>%",
+        "transpose" => "transpose <-60..60>",
+        "labels" => "labels sharps|flats|german|solfege|midi|keys",
+        "tuning" => "tuning <file.scl> [file.kbm]|off|chans <first> <last>",
+        "mpe" => "mpe [1-15]|off",
+        "bendrange" => "bendrange <semitones> [cents]",
+        "chord" => "chord <name|intervals>|off",
+        "arp" => "arp <pattern>|off|rate <bpm> [per beat]|gate <percent>|octaves <1-4>",
+        _ => "no arguments",
+    }
+}
+
+/// Words completed with Tab as the first argument of the commands
+const ARGS: &[(&str, &[&str])] = &[
+    ("vel", &["fixed", "human", "accent"]),
+    ("sustain", &["local", "cc"]),
+    ("labels", &["sharps", "flats", "german", "solfege", "midi", "keys"]),
+    ("tuning", &["off", "chans"]),
+    ("mpe", &["off"]),
+    ("arp", &["up", "down", "updown", "random", "played", "off", "rate", "gate", "octaves"]),
+    ("layout", &["default"]),
+    ("prog", &["names"]),
+    ("session", &["save", "load"]),
 ];
 
+/// Commands whose arguments are completed as file names
+const FILE_CMDS: &[&str] = &["save", "session", "play", "tuning", "layout", "prog"];
+
+/// How many commands are kept in the history file
+const HISTORY: usize = 500;
+
+/// Chord shapes by name, in semitones above the root
+const CHORDS: &[(&str, &[i32])] = &[
+    ("maj", &[0, 4, 7]),
+    ("min", &[0, 3, 7]),
+    ("dim", &[0, 3, 6]),
+    ("aug", &[0, 4, 8]),
+    ("sus2", &[0, 2, 7]),
+    ("sus4", &[0, 5, 7]),
+    ("7", &[0, 4, 7, 10]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("min7", &[0, 3, 7, 10]),
+    ("dim7", &[0, 3, 6, 9]),
+    ("5", &[0, 7]),
+    ("oct", &[0, 12]),
+];
+
+/// An entry of the device list
+#[derive(Debug, Clone)]
+pub enum Dev {
+    Midi(Device),
+    Internal,
+}
+
+impl Dev {
+    pub fn name(&self) -> &str {
+        match *self {
+            Dev::Midi(ref d) => &d.name,
+            Dev::Internal => "internal",
+        }
+    }
+
+    /// The line of the device list with the id and the interface
+    fn label(&self) -> String {
+        match *self {
+            Dev::Midi(ref d) => match d.interface {
+                Some(ref i) => format!("{}: {} ({})", d.id, d.name, i),
+                None => format!("{}: {}", d.id, d.name),
+            },
+            Dev::Internal => "internal synth".to_string(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Action {
-    Device(DeviceInfo),
+    Device(Device),
+    /// Play with the internal synth
+    Internal,
+    Input(Device),
+    /// Toggle forwarding the input port to the output
+    Thru,
     Chan(u8),
+    /// Transposition in semitones
+    Transpose(i32),
     Patch(Option<u8>, Option<u16>),
+    /// Control change: controller number and value
+    Control(u8, u8),
+    Velocity(u8),
+    Curve(Curve),
+    /// Hold note offs locally while the pedal is down instead of relying on CC#64
+    Sustain(bool),
+    Record,
+    Stop,
+    /// Save the recording to a file of the given SMF format, `None` is the `--record` path
+    Save(Option<String>, u16),
+    /// Save the device, the channels and the keyboard settings to a session file
+    SaveSession(String),
+    LoadSession(String),
+    /// Start or resume the playback, optionally loading a midi file
+    Play(Option<String>),
+    Pause,
+    /// Playback position in seconds
+    Seek(u32),
+    /// Playback speed in percents
+    Tempo(u16),
+    /// Toggle waiting for the notes to be played
+    Wait,
+    /// Load a Scala scale and an optional keyboard mapping, `None` means 12-TET
+    Tuning(Option<(String, Option<String>)>),
+    /// Channel range for the notes of a tuning
+    TuningChans(u8, u8),
+    /// MPE mode with the number of member channels, `None` turns it off
+    Mpe(Option<u8>),
+    /// Pitch bend range of the synth in semitones and cents
+    BendRange(u8, u8),
+    /// Play a chord with every key: its name and intervals, `None` plays single notes
+    Chord(Option<(String, Vec<i32>)>),
+    /// Arpeggiate the held notes in the pattern, `None` turns the arpeggiator off
+    Arp(Option<Pattern>),
+    /// Arpeggiator tempo in beats per minute and notes per beat
+    ArpRate(u16, u8),
+    /// Arpeggiator note length in percents of a step
+    ArpGate(u8),
+    /// Number of octaves the arpeggiator goes through
+    ArpOctaves(u8),
+    /// Show the note names spelled in the given way, `None` toggles the key names
+    Labels(Option<Spelling>),
+    /// Load the program names of an instrument definition file, `None` means General MIDI
+    Instruments(Option<(String, Option<String>)>),
+    /// Load a layout file, `None` means the built-in layout
+    Layout(Option<String>),
 }
 
 // Hello Commander. It is good to see you again.
 pub struct Commander<'a> {
-    pub midi: &'a PortMidi,
+    pub midi: &'a dyn Devices,
     input: String,
-    devs: Option<Vec<DeviceInfo>>,
+    devs: Option<Vec<Dev>>,
+    /// Programs matching the name typed, with their banks
+    progs: Option<Vec<(u8, Option<u16>, String)>>,
+    /// The selected device or program
     chosen: Option<usize>,
+    pub instruments: Instruments,
+    /// Whether the channel is 10, where the programs are drum kits
+    pub drums: bool,
     current: Option<String>,
+    /// Completions shown after Tab when there's more than one
+    hints: Vec<String>,
+    /// Why the last command failed
+    message: Option<String>,
+    history: Vec<String>,
+    /// Where the history is kept, `None` keeps it only in memory
+    history_file: Option<PathBuf>,
+    /// How far back in the history the command line is, 0 is the line being typed
+    back: usize,
+    draft: String,
 }
 
 impl<'a> Commander<'a> {
-    pub fn new(midi: &'a PortMidi) -> Self {
+    pub fn new(midi: &'a dyn Devices) -> Self {
         Commander {
             midi: midi,
             input: String::new(),
             devs: None,
+            progs: None,
             chosen: None,
+            instruments: Instruments::gm(),
+            drums: false,
             current: None,
+            hints: vec![],
+            message: None,
+            history: vec![],
+            history_file: None,
+            back: 0,
+            draft: String::new(),
+        }
+    }
+
+    /// The command line as it was typed
+    fn line(&self) -> String {
+        match self.current {
+            Some(ref cmd) if self.input.is_empty() => cmd.clone(),
+            Some(ref cmd) => format!("{} {}", cmd, self.input),
+            None => self.input.clone(),
         }
     }
 
+    /// Replaces the command line as if it was typed
+    fn set_line(&mut self, line: &str) {
+        self.input.clear();
+        self.current = None;
+        self.devs = None;
+        self.progs = None;
+        for ch in line.chars() {
+            self.feed(ch)
+        }
+    }
+
+    /// Lines of the device or the program list
+    fn picker(&self) -> Option<Vec<String>> {
+        match (&self.devs, &self.progs) {
+            (Some(devs), _) => Some(devs.iter().map(|d| d.label()).collect()),
+            (_, Some(progs)) => Some(progs.iter().map(|(p, bank, name)| match bank {
+                Some(b) => format!("{}/{}: {}", p, b, name),
+                None => format!("{}: {}", p, name),
+            }).collect()),
+            _ => None,
+        }
+    }
+
+    /// The name of the selected device or program
+    fn picked(&self) -> Option<String> {
+        let chosen = self.chosen.unwrap_or(0);
+        match (&self.devs, &self.progs) {
+            (Some(devs), _) => devs.get(chosen).map(|d| d.name().to_string()),
+            (_, Some(progs)) => progs.get(chosen).map(|(_, _, name)| name.clone()),
+            _ => None,
+        }
+    }
+
+    /// Selects the previous device or program in the list, otherwise goes back in the history
+    pub fn up(&mut self) {
+        match self.picker() {
+            Some(ref list) if !list.is_empty() => self.chosen = Some(self.chosen.unwrap_or(0).saturating_sub(1)),
+            _ => self.back(),
+        }
+    }
+
+    /// Selects the next device or program in the list, otherwise goes forward in the history
+    pub fn down(&mut self) {
+        match self.picker() {
+            Some(ref list) if !list.is_empty() =>
+                self.chosen = Some((self.chosen.unwrap_or(0) + 1).min(list.len() - 1)),
+            _ => self.forward(),
+        }
+    }
+
+    /// Goes to an older command of the history
+    fn back(&mut self) {
+        if self.back == self.history.len() { return }
+
+        if self.back == 0 { self.draft = self.line() }
+        self.back += 1;
+        let line = self.history[self.history.len() - self.back].clone();
+        self.set_line(&line)
+    }
+
+    /// Goes to a newer command of the history and then to the line being typed
+    fn forward(&mut self) {
+        if self.back == 0 { return }
+
+        self.back -= 1;
+        let line = if self.back == 0 {
+            self.draft.clone()
+        } else {
+            self.history[self.history.len() - self.back].clone()
+        };
+        self.set_line(&line)
+    }
+
+    /// Completes the command name or the word being typed of its argument: the selected
+    /// device or program, a channel, a keyword or a file name. When there are several
+    /// completions their common part is added and they are shown above the command line.
+    pub fn complete(&mut self) {
+        self.hints.clear();
+
+        if let Some(name) = self.picked() {
+            self.input = name;
+            return self.do_cmd()
+        }
+
+        let cmd = match self.current.clone() {
+            Some(cmd) => cmd,
+            None => {
+                let cmds: Vec<String> = CMDS.iter().filter(|c| c.starts_with(&*self.input))
+                    .map(|c| c.to_string()).collect();
+                if cmds.len() == 1 {
+                    self.input = cmds[0].clone();
+                    return self.feed(' ')
+                }
+                if let Some(prefix) = common_prefix(&cmds) { self.input = prefix }
+                self.hints = cmds;
+                return
+            },
+        };
+
+        let start = self.input.rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let first = !self.input[..start].contains(|c: char| !c.is_whitespace());
+        let word = self.input[start..].to_string();
+
+        let mut words: Vec<String> = match cmd.as_str() {
+            "chan" => (0..16).map(|c| c.to_string()).collect(),
+            "chord" if first => CHORDS.iter().map(|&(n, _)| n.to_string()).chain(Some("off".to_string())).collect(),
+            _ => ARGS.iter().find(|&&(c, _)| c == cmd)
+                .map(|&(_, args)| args.iter().map(|a| a.to_string()).collect())
+                .unwrap_or(vec![]),
+        };
+        if !first { words.clear() }
+
+        let mut matches: Vec<String> = words.into_iter().filter(|w| w.starts_with(&word)).collect();
+        let files = matches.is_empty() && FILE_CMDS.contains(&cmd.as_str());
+        if files {
+            matches = file_names(&word)
+        }
+
+        if matches.len() == 1 {
+            self.input.truncate(start);
+            self.input.push_str(&matches[0]);
+            if !(files && matches[0].ends_with('/')) { self.input.push(' ') }
+        } else if let Some(prefix) = common_prefix(&matches) {
+            if prefix.len() > word.len() {
+                self.input.truncate(start);
+                self.input.push_str(&prefix);
+            }
+            self.hints = matches;
+        }
+        self.do_cmd()
+    }
+
     pub fn feed(&mut self, ch: char) {
+        self.hints.clear();
+        self.message = None;
         match ch {
             '\u{8}' => {
                 if self.input.is_empty() && self.current.is_some() {
@@ -45,10 +391,11 @@ impl<'a> Commander<'a> {
                     ::std::mem::swap(&mut current, &mut self.current);
                     self.input = current.unwrap();
                     self.devs = None;
+                    self.progs = None;
+                    return
                 } else {
                     drop(self.input.pop());
                 }
-                return
             },
             ' ' if self.current.is_none() => {
                 if CMDS.contains(&&*self.input) {
@@ -57,6 +404,7 @@ impl<'a> Commander<'a> {
                     self.current = Some(current);
                 }
             },
+            ' ' if !self.input.is_empty() => self.input.push(ch),
             c if c > ' ' => self.input.push(ch),
             _ => return,
         }
@@ -65,33 +413,122 @@ impl<'a> Commander<'a> {
     }
 
     fn do_cmd(&mut self) {
-        match self.current.as_ref().map(|s| s.as_str()) {
-            Some("dev") => {
-                let input = self.input.clone();
-                if let Some(ref mut devs) = self.devs {
-                    devs.retain(|d| d.name().contains(&input));
+        match self.current.clone() {
+            Some(ref cmd) if cmd == "dev" || cmd == "thru" => {
+                let query = self.input.trim().to_string();
+                let mut devs = self.dev_list(cmd == "dev");
+
+                // A device can be picked by its id, otherwise the best matches go first
+                let by_id = |d: &Dev| match *d {
+                    Dev::Midi(ref d) => query.parse() == Ok(d.id),
+                    Dev::Internal => false,
+                };
+                if devs.iter().any(&by_id) {
+                    devs.retain(by_id)
                 } else {
-                    let mut devs = self.midi.devices().unwrap();
-                    devs.retain(|d| d.is_output());
-                    self.devs = Some(devs);
+                    let mut scored: Vec<_> = devs.into_iter()
+                        .filter_map(|d| fuzzy(d.name(), &query).map(|s| (s, d))).collect();
+                    scored.sort_by_key(|&(s, _)| s);
+                    devs = scored.into_iter().map(|(_, d)| d).collect();
                 }
+
+                self.devs = Some(devs);
+                self.chosen = None;
+            },
+            Some(ref cmd) if cmd == "prog" => {
+                // Numbers and the names command aren't searched
+                let query = self.input.trim().to_string();
+                let typed = query.is_empty() || query.starts_with(|c: char| c.is_ascii_digit() || c == '/')
+                    || query == "names" || query.starts_with("names ");
+                self.progs = if typed { None } else {
+                    let mut scored: Vec<_> = self.instruments.programs(self.drums).into_iter()
+                        .filter_map(|p| fuzzy(&p.2, &query).map(|s| (s, p))).collect();
+                    scored.sort_by_key(|&(s, _)| s);
+                    Some(scored.into_iter().map(|(_, p)| p).collect())
+                };
+                self.chosen = None;
             },
             _ => return,
         }
     }
 
-    pub fn emit(&mut self) -> Option<Action> {
+    /// All the output or input devices
+    fn dev_list(&self, output: bool) -> Vec<Dev> {
+        let mut devs: Vec<_> = self.midi.list().into_iter()
+            .filter(|d| d.output == output).map(Dev::Midi).collect();
+        if output { devs.push(Dev::Internal) }
+        devs
+    }
+
+    /// Loads the commands of the previous sessions and appends the new ones to the file
+    pub fn keep_history(&mut self, path: PathBuf) {
+        self.history = load_history(&path);
+        self.history_file = Some(path);
+    }
+
+    /// Shows why the command failed until something is typed
+    pub fn show_error(&mut self, msg: String) {
+        self.message = Some(msg)
+    }
+
+    /// The action of the command line, `None` if it's empty
+    pub fn emit(&mut self) -> Result<Option<Action>> {
+        // Commands without arguments don't need a space after them
+        if self.current.is_none() && CMDS.contains(&&*self.input) {
+            let mut current = String::new();
+            ::std::mem::swap(&mut current, &mut self.input);
+            self.current = Some(current);
+        }
+
+        // The device or the program picked from the list is remembered by its name
+        let line = match (self.current.as_ref(), self.picked()) {
+            (Some(cmd), Some(name)) => format!("{} {}", cmd, name),
+            _ => self.line(),
+        };
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            if let Some(ref path) = self.history_file { save_history(path, &line) }
+            self.history.push(line);
+        }
+        self.back = 0;
+
         let act;
         {
             let cmd = self.current.as_ref().map(|s| s.as_str());
             act = match (cmd, &self.devs) {
-                (Some("dev"), &Some(ref devs)) => {
-                    devs.get(0).map(|d| Action::Device(d.clone()))
+                (Some("dev"), Some(devs)) => match devs.get(self.chosen.unwrap_or(0)) {
+                    Some(Dev::Midi(d)) => Some(Action::Device(d.clone())),
+                    Some(Dev::Internal) => Some(Action::Internal),
+                    None => None,
+                },
+                (Some("thru"), _) if self.input.is_empty() => Some(Action::Thru),
+                (Some("thru"), Some(devs)) => match devs.get(self.chosen.unwrap_or(0)) {
+                    Some(Dev::Midi(d)) => Some(Action::Input(d.clone())),
+                    _ => None,
                 },
                 (Some("chan"), _) => {
-                    if let Ok(ch) = self.input.parse() {
-                        Some(Action::Chan(ch))
-                    } else { None }
+                    match self.input.trim().parse() {
+                        Ok(ch) if ch < 16 => Some(Action::Chan(ch)),
+                        _ => None,
+                    }
+                },
+                (Some("transpose"), _) => {
+                    match self.input.trim().trim_start_matches('+').parse() {
+                        Ok(n) if (-60..=60).contains(&n) => Some(Action::Transpose(n)),
+                        _ => None,
+                    }
+                },
+                (Some("prog"), _) if self.progs.is_some() => {
+                    self.progs.as_ref().and_then(|progs| progs.get(self.chosen.unwrap_or(0)))
+                        .map(|&(p, bank, _)| Action::Patch(Some(p), bank))
+                },
+                (Some("prog"), _) if self.input.trim_start().starts_with("names") => {
+                    let mut words = self.input.split_whitespace().skip(1);
+                    match (words.next(), words.collect::<Vec<_>>().join(" ")) {
+                        (Some("gm"), ref rest) if rest.is_empty() => Some(Action::Instruments(None)),
+                        (Some(path), ref rest) if rest.is_empty() => Some(Action::Instruments(Some((path.to_string(), None)))),
+                        (Some(path), rest) => Some(Action::Instruments(Some((path.to_string(), Some(rest))))),
+                        (None, _) => None,
+                    }
                 },
                 (Some("prog"), _) => {
                     let mut split = self.input.split('/');
@@ -105,34 +542,255 @@ impl<'a> Commander<'a> {
                             Some(Action::Patch(Some(p), Some(b))),
                         _ => None,
                     }
-                }
+                },
+                (Some("cc"), _) => {
+                    let mut split = self.input.split_whitespace();
+                    let cc: Option<u8> = split.next().and_then(|s| s.parse().ok());
+                    let value: Option<u8> = split.next().and_then(|s| s.parse().ok());
+
+                    match (cc, value) {
+                        (Some(c), Some(v)) if c < 128 && v < 128 => Some(Action::Control(c, v)),
+                        _ => None,
+                    }
+                },
+                (Some("vel"), _) => {
+                    let mut split = self.input.split_whitespace();
+                    let first = split.next();
+                    let args: Option<Vec<i8>> = split.map(|s| s.parse().ok()).collect();
+
+                    match (first, args) {
+                        (Some("fixed"), _) => Some(Action::Curve(Curve::Fixed)),
+                        (Some("human"), Some(ref a)) if a.len() == 1 && a[0] >= 0 =>
+                            Some(Action::Curve(Curve::Human(a[0] as u8))),
+                        (Some("accent"), Some(ref a)) if a.len() <= 4 => {
+                            let mut accents = [0; 4];
+                            accents[..a.len()].copy_from_slice(a);
+                            Some(Action::Curve(Curve::Accent(accents)))
+                        },
+                        (Some(v), _) => match v.parse() {
+                            Ok(v) if v > 0 && v < 128 => Some(Action::Velocity(v)),
+                            _ => None,
+                        },
+                        _ => None,
+                    }
+                },
+                (Some("sustain"), _) => {
+                    match self.input.trim() {
+                        "local" => Some(Action::Sustain(true)),
+                        "cc" => Some(Action::Sustain(false)),
+                        _ => None,
+                    }
+                },
+                (Some("rec"), _) => Some(Action::Record),
+                (Some("stop"), _) => Some(Action::Stop),
+                (Some("save"), _) => {
+                    let input = self.input.trim();
+                    let (path, format) = match input.rfind(' ') {
+                        Some(i) if &input[i..] == " 0" || &input[i..] == " 1" =>
+                            (input[..i].trim(), if &input[i..] == " 0" { 0 } else { 1 }),
+                        _ => (input, 0),
+                    };
+
+                    if path.is_empty() {
+                        Some(Action::Save(None, format))
+                    } else {
+                        Some(Action::Save(Some(path.to_string()), format))
+                    }
+                },
+                (Some("session"), _) => {
+                    let input = self.input.trim();
+                    let (verb, path) = match input.find(' ') {
+                        Some(i) => (&input[..i], input[i..].trim()),
+                        None => (input, ""),
+                    };
+                    match (verb, path) {
+                        (_, "") => None,
+                        ("save", path) => Some(Action::SaveSession(path.to_string())),
+                        ("load", path) => Some(Action::LoadSession(path.to_string())),
+                        _ => None,
+                    }
+                },
+                (Some("play"), _) => {
+                    match self.input.trim() {
+                        "" => Some(Action::Play(None)),
+                        path => Some(Action::Play(Some(path.to_string()))),
+                    }
+                },
+                (Some("pause"), _) => Some(Action::Pause),
+                (Some("seek"), _) => {
+                    // Either seconds or minutes:seconds
+                    let mut split = self.input.trim().rsplit(':');
+                    let secs: Option<u32> = split.next().and_then(|s| s.parse().ok());
+                    let mins: Option<u32> = match split.next() {
+                        Some(m) => m.parse().ok(),
+                        None => Some(0),
+                    };
+
+                    // The time has to fit in milliseconds
+                    match (mins, secs) {
+                        (Some(m), Some(s)) => m.checked_mul(60).and_then(|m| m.checked_add(s))
+                            .filter(|t| t.checked_mul(1000).is_some()).map(Action::Seek),
+                        _ => None,
+                    }
+                },
+                (Some("tempo"), _) => {
+                    match self.input.trim().trim_end_matches('%').parse() {
+                        Ok(t) if (10..=400).contains(&t) => Some(Action::Tempo(t)),
+                        _ => None,
+                    }
+                },
+                (Some("wait"), _) => Some(Action::Wait),
+                (Some("tuning"), _) => {
+                    let mut words = self.input.split_whitespace();
+                    match (words.next(), words.next(), words.next()) {
+                        (Some("off"), None, _) => Some(Action::Tuning(None)),
+                        (Some("chans"), Some(lo), Some(hi)) => match (lo.parse(), hi.parse()) {
+                            (Ok(lo), Ok(hi)) if lo <= hi && hi < 16 => Some(Action::TuningChans(lo, hi)),
+                            _ => None,
+                        },
+                        (Some(scl), kbm, None) =>
+                            Some(Action::Tuning(Some((scl.to_string(), kbm.map(|k| k.to_string()))))),
+                        _ => None,
+                    }
+                },
+                (Some("mpe"), _) => {
+                    match self.input.trim() {
+                        "" => Some(Action::Mpe(Some(15))),
+                        "off" => Some(Action::Mpe(None)),
+                        n => match n.parse() {
+                            Ok(n) if (1..=15).contains(&n) => Some(Action::Mpe(Some(n))),
+                            _ => None,
+                        },
+                    }
+                },
+                (Some("bendrange"), _) => {
+                    let mut words = self.input.split_whitespace();
+                    let semis = words.next().and_then(|s| s.parse::<u8>().ok());
+                    let cents = words.next().map(|c| c.parse::<u8>().ok()).unwrap_or(Some(0));
+                    match (semis, cents) {
+                        (Some(s), Some(c)) if s < 128 && c < 100 && s + c > 0 => Some(Action::BendRange(s, c)),
+                        _ => None,
+                    }
+                },
+                (Some("chord"), _) => {
+                    match self.input.trim() {
+                        "" => None,
+                        "off" => Some(Action::Chord(None)),
+                        name => match CHORDS.iter().find(|&&(n, _)| n == name) {
+                            Some(&(n, intervals)) => Some(Action::Chord(Some((n.to_string(), intervals.to_vec())))),
+                            // A list of intervals like `0 4 7 11` or `-12,0,7`
+                            None => {
+                                let mut intervals = name.split(|c: char| c == ',' || c.is_whitespace())
+                                    .filter(|i| !i.is_empty()).map(|i| i.parse::<i32>().ok())
+                                    .collect::<Option<Vec<_>>>().unwrap_or(vec![]);
+                                intervals.sort();
+                                intervals.dedup();
+
+                                if intervals.is_empty() || intervals.iter().any(|i| i.abs() > 127) {
+                                    None
+                                } else {
+                                    let name = intervals.iter().map(|i| i.to_string())
+                                        .collect::<Vec<_>>().join(" ");
+                                    Some(Action::Chord(Some((name, intervals))))
+                                }
+                            },
+                        },
+                    }
+                },
+                (Some("arp"), _) => {
+                    let mut words = self.input.split_whitespace();
+                    match (words.next(), words.next(), words.next()) {
+                        (Some("off"), None, _) => Some(Action::Arp(None)),
+                        (Some("rate"), Some(bpm), per_beat) => {
+                            match (bpm.parse(), per_beat.map(|n| n.parse()).unwrap_or(Ok(4))) {
+                                (Ok(bpm), Ok(n)) if (10..=400).contains(&bpm) && (1..=16).contains(&n) =>
+                                    Some(Action::ArpRate(bpm, n)),
+                                _ => None,
+                            }
+                        },
+                        (Some("gate"), Some(gate), None) => match gate.trim_end_matches('%').parse() {
+                            Ok(gate) if (1..=100).contains(&gate) => Some(Action::ArpGate(gate)),
+                            _ => None,
+                        },
+                        (Some("octaves"), Some(n), None) => match n.parse() {
+                            Ok(n) if (1..=4).contains(&n) => Some(Action::ArpOctaves(n)),
+                            _ => None,
+                        },
+                        (Some(pattern), None, _) => Pattern::parse(pattern).map(|p| Action::Arp(Some(p))),
+                        _ => None,
+                    }
+                },
+                (Some("labels"), _) => {
+                    match self.input.trim() {
+                        "keys" => Some(Action::Labels(None)),
+                        s => Spelling::parse(s).map(|s| Action::Labels(Some(s))),
+                    }
+                },
+                (Some("layout"), _) => {
+                    match self.input.trim() {
+                        "" => None,
+                        "default" => Some(Action::Layout(None)),
+                        path => Some(Action::Layout(Some(path.to_string()))),
+                    }
+                },
                 _ => None,
             };
         }
 
+        let input = self.input.trim().to_string();
+        let result = match (act, self.current.as_ref()) {
+            (Some(act), _) => Ok(Some(act)),
+            (None, None) if input.is_empty() => Ok(None),
+            (None, None) => Err(Error::UnknownCommand(input)),
+            (None, Some(cmd)) if (cmd == "dev" || cmd == "thru") && !input.is_empty() => Err(Error::NoDevice(input)),
+            (None, Some(cmd)) if cmd == "prog" && self.progs.is_some() => Err(Error::NoProgram(input)),
+            (None, Some(cmd)) => Err(Error::Usage(usage(cmd))),
+        };
+
         self.input.clear();
         self.devs = None;
+        self.progs = None;
+        self.chosen = None;
         self.current = None;
 
-        act
+        result
     }
 
     pub fn text(&self) -> String {
         let mut text = String::new();
 
-        let cmdline = match self.current {
+        let mut cmdline = match self.current {
             Some(ref cmd) => format!("{}> {}", cmd, self.input),
             None => format!("> {}", self.input),
         };
 
-        if let Some(ref devs) = self.devs {
-            use std::iter::repeat;
-            for l in devs.iter().map(|d| d.name()).chain(repeat(&"".to_string())).take(3) {
-                text.push_str(l);
+        if let Some(list) = self.picker() {
+            // Three entries around the selected one
+            let chosen = self.chosen.unwrap_or(0);
+            let top = chosen.saturating_sub(1).min(list.len().saturating_sub(3));
+            for i in top..top + 3 {
+                match list.get(i) {
+                    Some(l) if i == chosen => text.push_str(&format!("> {}", l)),
+                    Some(l) => text.push_str(&format!("  {}", l)),
+                    None => (),
+                }
                 text.push('\n');
             }
+            if list.len() > 3 {
+                cmdline.push_str(&format!("    {}/{}", chosen + 1, list.len()));
+            }
         } else {
-            text.push_str("\n\n\n");
+            let mut lines = vec![String::new(); 3];
+            if let Some(ref msg) = self.message { lines[0] = msg.clone() }
+            for (i, hint) in self.hints.iter().enumerate().take(24) {
+                let line = &mut lines[i / 8];
+                if !line.is_empty() { line.push_str("  ") }
+                line.push_str(hint);
+            }
+            for l in lines {
+                text.push_str(&l);
+                text.push('\n');
+            }
         }
 
         text.push_str(&cmdline);
@@ -140,6 +798,85 @@ impl<'a> Commander<'a> {
     }
 }
 
+/// How well a name matches the typed letters, lower is better: the names containing them
+/// go first, then the ones with the letters in the same order. Case is ignored.
+fn fuzzy(name: &str, query: &str) -> Option<usize> {
+    let (name, query) = (name.to_lowercase(), query.to_lowercase());
+    if let Some(i) = name.find(&query) {
+        return Some(i)
+    }
+
+    let mut chars = name.char_indices();
+    let mut span = (None, 0);
+    for q in query.chars() {
+        let (i, _) = chars.find(|&(_, c)| c == q)?;
+        span = (span.0.or(Some(i)), i);
+    }
+    Some(name.len() + span.1 - span.0.unwrap_or(0))
+}
+
+/// The longest start shared by all the words, `None` if there are none
+fn common_prefix(words: &[String]) -> Option<String> {
+    let first = words.first()?;
+    let len = first.char_indices().map(|(i, c)| i + c.len_utf8())
+        .take_while(|&i| words.iter().all(|w| w.starts_with(&first[..i])))
+        .last().unwrap_or(0);
+    Some(first[..len].to_string())
+}
+
+/// Files starting with the path, the directories end with a slash
+fn file_names(path: &str) -> Vec<String> {
+    let (dir, prefix) = match path.rfind('/') {
+        Some(i) => path.split_at(i + 1),
+        None => ("", path),
+    };
+    let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut names: Vec<String> = entries.filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None
+            }
+            let slash = if e.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, slash))
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+pub fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".vmjk_history"))
+}
+
+/// The last commands run in the previous sessions
+fn load_history(path: &Path) -> Vec<String> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return vec![],
+    };
+
+    let mut lines: Vec<String> = BufReader::new(file).lines().map_while(|l| l.ok())
+        .filter(|l| !l.trim().is_empty()).collect();
+    let old = lines.len().saturating_sub(HISTORY);
+    if old > 0 {
+        lines.drain(..old);
+        drop(fs::write(path, lines.join("\n") + "\n"))
+    }
+    lines
+}
+
+fn save_history(path: &Path, line: &str) {
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        drop(writeln!(file, "{}", line))
+    }
+}
+
+#[cfg(feature = "gui")]
 pub struct CmdFrame<'a> {
     text: String,
     view: (f32, f32),
@@ -147,6 +884,7 @@ pub struct CmdFrame<'a> {
     font_size: f32,
 }
 
+#[cfg(feature = "gui")]
 impl<'a> CmdFrame<'a> {
     pub fn new(text: String, view: (f32, f32), font: &'a Font, font_size: u32) -> Self {
         CmdFrame {
@@ -158,7 +896,7 @@ impl<'a> CmdFrame<'a> {
     }
 }
 
-
+#[cfg(feature = "gui")]
 impl<'a> Drawable for CmdFrame<'a> {
     fn draw<RT: RenderTarget>(&self, target: &mut RT, rs: &mut RenderStates) {
         let (width, y) = self.view;
@@ -174,3 +912,143 @@ impl<'a> Drawable for CmdFrame<'a> {
         text.draw(target, rs);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use portmidi::InputPort;
+
+    use output::Output;
+
+    use super::*;
+
+    struct Synths;
+
+    impl Devices for Synths {
+        fn list(&self) -> Vec<Device> {
+            let dev = |id: i32, name: &str, output: bool| Device {
+                id: id,
+                name: name.to_string(),
+                interface: Some("ALSA".to_string()),
+                output: output,
+            };
+            vec![dev(0, "Midi Through", true), dev(1, "Keystation", false), dev(2, "FluidSynth", true)]
+        }
+
+        fn open_output(&self, dev: &Device) -> Result<Box<dyn Output>> {
+            Err(Error::NoDevice(dev.name.clone()))
+        }
+
+        fn open_input(&self, dev: &Device) -> Result<InputPort> {
+            Err(Error::NoDevice(dev.name.clone()))
+        }
+    }
+
+    fn run(cmd: &mut Commander, line: &str) -> Result<Option<Action>> {
+        for c in line.chars() { cmd.feed(c) }
+        cmd.emit()
+    }
+
+    #[test]
+    fn actions() {
+        let synths = Synths;
+        let mut cmd = Commander::new(&synths);
+
+        assert!(matches!(run(&mut cmd, ""), Ok(None)));
+        assert!(matches!(run(&mut cmd, "transpose -12"), Ok(Some(Action::Transpose(-12)))));
+        assert!(matches!(run(&mut cmd, "chan 15"), Ok(Some(Action::Chan(15)))));
+        assert!(matches!(run(&mut cmd, "stop"), Ok(Some(Action::Stop))));
+        assert!(matches!(run(&mut cmd, "seek 1:30"), Ok(Some(Action::Seek(90)))));
+        assert!(matches!(run(&mut cmd, "save my take.mid 1"), Ok(Some(Action::Save(Some(ref p), 1))) if p == "my take.mid"));
+        assert!(matches!(run(&mut cmd, "save"), Ok(Some(Action::Save(None, 0)))));
+        assert!(matches!(run(&mut cmd, "session save a.toml"), Ok(Some(Action::SaveSession(ref p))) if p == "a.toml"));
+        assert!(matches!(run(&mut cmd, "session load a.toml"), Ok(Some(Action::LoadSession(ref p))) if p == "a.toml"));
+        assert!(matches!(run(&mut cmd, "vel accent 10 0 -10"), Ok(Some(Action::Curve(Curve::Accent([10, 0, -10, 0]))))));
+        assert!(matches!(run(&mut cmd, "chord 7 0 4 0"), Ok(Some(Action::Chord(Some((ref n, _))))) if n == "0 4 7"));
+        assert!(matches!(run(&mut cmd, "arp rate 90 3"), Ok(Some(Action::ArpRate(90, 3)))));
+        assert!(matches!(run(&mut cmd, "tuning chans 1 8"), Ok(Some(Action::TuningChans(1, 8)))));
+    }
+
+    #[test]
+    fn errors() {
+        let synths = Synths;
+        let mut cmd = Commander::new(&synths);
+
+        assert!(matches!(run(&mut cmd, "quit"), Err(Error::UnknownCommand(ref c)) if c == "quit"));
+        assert!(matches!(run(&mut cmd, "chan 16"), Err(Error::Usage(_))));
+        assert!(matches!(run(&mut cmd, "transpose 61"), Err(Error::Usage(_))));
+        assert!(matches!(run(&mut cmd, "session a.toml"), Err(Error::Usage(_))));
+        assert!(matches!(run(&mut cmd, "session save"), Err(Error::Usage(_))));
+        assert!(matches!(run(&mut cmd, "seek 99999999"), Err(Error::Usage(_))));
+        assert!(matches!(run(&mut cmd, "mpe 16"), Err(Error::Usage(_))));
+        assert!(matches!(run(&mut cmd, "dev yamaha"), Err(Error::NoDevice(ref d)) if d == "yamaha"));
+    }
+
+    #[test]
+    fn devices() {
+        let synths = Synths;
+        let mut cmd = Commander::new(&synths);
+
+        // Found by the letters in order, by the id or picked from the list
+        assert!(matches!(run(&mut cmd, "dev flsy"), Ok(Some(Action::Device(ref d))) if d.id == 2));
+        assert!(matches!(run(&mut cmd, "dev 0"), Ok(Some(Action::Device(ref d))) if d.id == 0));
+        assert!(matches!(run(&mut cmd, "dev internal"), Ok(Some(Action::Internal))));
+        assert!(matches!(run(&mut cmd, "thru key"), Ok(Some(Action::Input(ref d))) if d.id == 1));
+
+        for c in "dev ".chars() { cmd.feed(c) }
+        cmd.down();
+        assert!(matches!(cmd.emit(), Ok(Some(Action::Device(ref d))) if d.id == 2));
+        // Remembered by the name
+        assert_eq!(cmd.history.last().map(|h| h.as_str()), Some("dev FluidSynth"));
+    }
+
+    #[test]
+    fn completion() {
+        let synths = Synths;
+        let mut cmd = Commander::new(&synths);
+
+        for c in "tra".chars() { cmd.feed(c) }
+        cmd.complete();
+        assert_eq!(cmd.current.as_deref(), Some("transpose"));
+        drop(cmd.emit());
+
+        // Several commands: their common start is kept and they are shown
+        for c in "ch".chars() { cmd.feed(c) }
+        cmd.complete();
+        assert_eq!(cmd.input, "ch");
+        assert_eq!(cmd.hints, vec!["chan".to_string(), "chord".to_string()]);
+        drop(cmd.emit());
+
+        for c in "session s".chars() { cmd.feed(c) }
+        cmd.complete();
+        assert_eq!(cmd.input, "save ");
+        drop(cmd.emit());
+
+        for c in "dev flu".chars() { cmd.feed(c) }
+        cmd.complete();
+        assert_eq!(cmd.input, "FluidSynth");
+        drop(cmd.emit());
+
+        for c in "chan 1".chars() { cmd.feed(c) }
+        cmd.complete();
+        assert_eq!(cmd.hints.len(), 7);
+    }
+
+    #[test]
+    fn history() {
+        let synths = Synths;
+        let mut cmd = Commander::new(&synths);
+
+        drop(run(&mut cmd, "vel 100"));
+        drop(run(&mut cmd, "vel 100"));
+        drop(run(&mut cmd, "chan 3"));
+        assert_eq!(cmd.history.len(), 2);
+
+        for c in "tempo".chars() { cmd.feed(c) }
+        cmd.up();
+        cmd.up();
+        assert_eq!(cmd.line(), "vel 100");
+        cmd.down();
+        cmd.down();
+        assert_eq!(cmd.line(), "tempo");
+    }
+}
@@ -0,0 +1,64 @@
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use portmidi;
+
+/// What went wrong with a command or the midi output
+#[derive(Debug)]
+pub enum Error {
+    /// A command that doesn't exist
+    UnknownCommand(String),
+    /// Wrong arguments, with the usage of the command
+    Usage(&'static str),
+    /// No device matches what was typed
+    NoDevice(String),
+    /// No program name matches what was typed
+    NoProgram(String),
+    Midi(portmidi::Error),
+    /// The output stopped, e.g. the port vanished
+    Disconnected(String),
+    /// A file that couldn't be read or written
+    File(String, io::Error),
+    /// The internal synth was chosen without `--synth`
+    NoSynth,
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownCommand(ref cmd) => write!(f, "unknown command: {}", cmd),
+            Error::Usage(usage) => write!(f, "usage: {}", usage),
+            Error::NoDevice(ref name) => write!(f, "no device matches \"{}\"", name),
+            Error::NoProgram(ref name) => write!(f, "no program matches \"{}\"", name),
+            Error::Midi(ref e) => write!(f, "midi: {}", e),
+            Error::Disconnected(ref name) => write!(f, "{} stopped", name),
+            Error::File(ref path, ref e) => write!(f, "{}: {}", path, e),
+            Error::NoSynth => write!(f, "the internal synth needs --synth <file>"),
+        }
+    }
+}
+
+impl From<portmidi::Error> for Error {
+    fn from(e: portmidi::Error) -> Self {
+        Error::Midi(e)
+    }
+}
+
+/// Appends the error to `~/.vmjk_log` with the time in seconds since the epoch
+pub fn log(e: &Error) {
+    let path = match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".vmjk_log"),
+        None => return,
+    };
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        drop(writeln!(file, "{} {}", time, e))
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// The General MIDI programs
+const PROGRAMS: [&str; 128] = [
+    "Acoustic Grand Piano", "Bright Acoustic Piano", "Electric Grand Piano", "Honky-tonk Piano",
+    "Electric Piano 1", "Electric Piano 2", "Harpsichord", "Clavinet",
+    "Celesta", "Glockenspiel", "Music Box", "Vibraphone",
+    "Marimba", "Xylophone", "Tubular Bells", "Dulcimer",
+    "Drawbar Organ", "Percussive Organ", "Rock Organ", "Church Organ",
+    "Reed Organ", "Accordion", "Harmonica", "Tango Accordion",
+    "Acoustic Guitar (nylon)", "Acoustic Guitar (steel)", "Electric Guitar (jazz)", "Electric Guitar (clean)",
+    "Electric Guitar (muted)", "Overdriven Guitar", "Distortion Guitar", "Guitar Harmonics",
+    "Acoustic Bass", "Electric Bass (finger)", "Electric Bass (pick)", "Fretless Bass",
+    "Slap Bass 1", "Slap Bass 2", "Synth Bass 1", "Synth Bass 2",
+    "Violin", "Viola", "Cello", "Contrabass",
+    "Tremolo Strings", "Pizzicato Strings", "Orchestral Harp", "Timpani",
+    "String Ensemble 1", "String Ensemble 2", "Synth Strings 1", "Synth Strings 2",
+    "Choir Aahs", "Voice Oohs", "Synth Voice", "Orchestra Hit",
+    "Trumpet", "Trombone", "Tuba", "Muted Trumpet",
+    "French Horn", "Brass Section", "Synth Brass 1", "Synth Brass 2",
+    "Soprano Sax", "Alto Sax", "Tenor Sax", "Baritone Sax",
+    "Oboe", "English Horn", "Bassoon", "Clarinet",
+    "Piccolo", "Flute", "Recorder", "Pan Flute",
+    "Blown Bottle", "Shakuhachi", "Whistle", "Ocarina",
+    "Lead 1 (square)", "Lead 2 (sawtooth)", "Lead 3 (calliope)", "Lead 4 (chiff)",
+    "Lead 5 (charang)", "Lead 6 (voice)", "Lead 7 (fifths)", "Lead 8 (bass + lead)",
+    "Pad 1 (new age)", "Pad 2 (warm)", "Pad 3 (polysynth)", "Pad 4 (choir)",
+    "Pad 5 (bowed)", "Pad 6 (metallic)", "Pad 7 (halo)", "Pad 8 (sweep)",
+    "FX 1 (rain)", "FX 2 (soundtrack)", "FX 3 (crystal)", "FX 4 (atmosphere)",
+    "FX 5 (brightness)", "FX 6 (goblins)", "FX 7 (echoes)", "FX 8 (sci-fi)",
+    "Sitar", "Banjo", "Shamisen", "Koto",
+    "Kalimba", "Bagpipe", "Fiddle", "Shanai",
+    "Tinkle Bell", "Agogo", "Steel Drums", "Woodblock",
+    "Taiko Drum", "Melodic Tom", "Synth Drum", "Reverse Cymbal",
+    "Guitar Fret Noise", "Breath Noise", "Seashore", "Bird Tweet",
+    "Telephone Ring", "Helicopter", "Applause", "Gunshot",
+];
+
+/// The drum kits of GS and GM2 on the channel 10
+const DRUM_KITS: &[(u8, &str)] = &[
+    (0, "Standard Kit"),
+    (8, "Room Kit"),
+    (16, "Power Kit"),
+    (24, "Electronic Kit"),
+    (25, "TR-808 Kit"),
+    (32, "Jazz Kit"),
+    (40, "Brush Kit"),
+    (48, "Orchestra Kit"),
+    (56, "SFX Kit"),
+    (127, "CM-64/CM-32L Kit"),
+];
+
+/// The drum kits of XG, on any channel with the bank 127
+const XG_DRUM_KITS: &[(u8, &str)] = &[
+    (0, "Standard Kit"),
+    (1, "Standard Kit 2"),
+    (8, "Room Kit"),
+    (16, "Rock Kit"),
+    (24, "Electro Kit"),
+    (25, "Analog Kit"),
+    (32, "Jazz Kit"),
+    (40, "Brush Kit"),
+    (48, "Classic Kit"),
+];
+
+/// Bank of the XG drum kits, the MSB shifted by 7 bits. GM2 has the GS kits in the bank
+/// 120, which are found as any bank.
+const XG_DRUMS: u16 = 127 << 7;
+
+/// Program names by bank, `None` is any bank
+type Banks = Vec<(Option<u16>, Vec<(u8, String)>)>;
+
+/// The program names of a synth
+#[derive(Debug)]
+pub struct Instruments {
+    patches: Banks,
+    /// The programs of the channel 10
+    drums: Banks,
+}
+
+impl Instruments {
+    /// The General MIDI programs and the GS, GM2 and XG drum kits
+    pub fn gm() -> Self {
+        let list = |names: &[(u8, &str)]| names.iter().map(|&(p, n)| (p, n.to_string())).collect::<Vec<_>>();
+        let programs = PROGRAMS.iter().enumerate().map(|(p, n)| (p as u8, n.to_string())).collect();
+
+        Instruments {
+            patches: vec![(None, programs)],
+            drums: vec![
+                (Some(XG_DRUMS), list(XG_DRUM_KITS)),
+                (None, list(DRUM_KITS)),
+            ],
+        }
+    }
+
+    /// Loads an instrument of a Cakewalk instrument definition file (`.ins`), the first
+    /// one if no name is given.
+    ///
+    /// Only the patch names are read: the `.Patch Names` lists, which may be based on
+    /// other lists, and the `Patch[bank]` entries of the `.Instrument Definitions`.
+    pub fn load<P: AsRef<Path>>(path: P, instrument: Option<&str>) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let mut section = String::new();
+        let mut lists: Vec<(String, Vec<(u8, String)>)> = vec![];
+        let mut instruments = vec![];
+
+        for line in file.lines() {
+            let line = line?;
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() { continue }
+
+            if line.starts_with('.') {
+                section = line.to_lowercase();
+                continue
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                let heading = line[1..line.len() - 1].to_string();
+                match section.as_str() {
+                    ".patch names" => lists.push((heading, vec![])),
+                    ".instrument definitions" => instruments.push((heading, vec![])),
+                    _ => (),
+                }
+                continue
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim().to_string()),
+                None => continue,
+            };
+            match section.as_str() {
+                ".patch names" => {
+                    let based = if key.eq_ignore_ascii_case("BasedOn") {
+                        lists.iter().find(|(n, _)| *n == value).map(|(_, l)| l.clone())
+                    } else { None };
+                    let list = match lists.last_mut() {
+                        Some(&mut (_, ref mut list)) => list,
+                        None => continue,
+                    };
+
+                    if let Some(based) = based {
+                        list.extend(based)
+                    } else if let Ok(p) = key.parse::<u8>() {
+                        list.retain(|&(n, _)| n != p);
+                        list.push((p, value))
+                    }
+                },
+                ".instrument definitions" if key.starts_with("Patch[") && key.ends_with(']') => {
+                    let bank = match &key[6..key.len() - 1] {
+                        "*" => None,
+                        b => match b.parse::<u16>() {
+                            Ok(b) if b < 16384 => Some(b),
+                            _ => continue,
+                        },
+                    };
+                    if let Some(&mut (_, ref mut banks)) = instruments.last_mut() {
+                        banks.push((bank, value))
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        let found = match instrument {
+            Some(name) => instruments.into_iter().find(|(n, _)| n.eq_ignore_ascii_case(name)),
+            None => instruments.into_iter().next(),
+        };
+        let banks = match found {
+            Some((_, banks)) => banks,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "no such instrument")),
+        };
+
+        let patches = banks.into_iter()
+            .filter_map(|(bank, list)| {
+                lists.iter().find(|(n, _)| *n == list).map(|(_, l)| (bank, l.clone()))
+            })
+            .collect();
+
+        Ok(Instruments {
+            patches: patches,
+            drums: vec![],
+        })
+    }
+
+    /// The name of a program in a bank, the banks of the drum kits are searched first
+    /// on the channel 10
+    pub fn program(&self, drums: bool, patch: u8, bank: Option<u16>) -> Option<&str> {
+        let (first, second) = if drums { (&self.drums, &self.patches) } else { (&self.patches, &self.drums) };
+        let any = if drums && !self.drums.is_empty() { &self.drums } else { &self.patches };
+
+        let exact = first.iter().chain(second.iter()).find(|&&(b, _)| b.is_some() && b == bank);
+        let list = match exact {
+            Some((_, list)) => list,
+            None => match any.iter().find(|&&(b, _)| b.is_none()).or(any.first()) {
+                Some((_, list)) => list,
+                None => return None,
+            },
+        };
+
+        list.iter().find(|&&(p, _)| p == patch).map(|(_, n)| n.as_str())
+    }
+
+    /// All the programs of the channel with their banks, `None` keeps the bank
+    pub fn programs(&self, drums: bool) -> Vec<(u8, Option<u16>, String)> {
+        let banks = if drums && !self.drums.is_empty() { &self.drums } else { &self.patches };
+        banks.iter()
+            .flat_map(|&(bank, ref list)| list.iter().map(move |&(p, ref n)| (p, bank, n.clone())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    const INS: &str = "; A synth with two banks
+.Patch Names
+
+[General]
+0=Piano
+1=Bright Piano
+
+[Variation]
+BasedOn=General
+1=Bright Piano 2 ; replaces the patch of General
+2=Honky-tonk
+
+.Instrument Definitions
+
+[Synth A]
+Patch[*]=General
+Patch[8]=Variation
+
+[Synth B]
+Patch[*]=Variation
+";
+
+    fn load(instrument: Option<&str>) -> io::Result<Instruments> {
+        let path = env::temp_dir().join(format!("vmjk-{}-{}.ins", process::id(), instrument.unwrap_or("first")));
+        fs::write(&path, INS).unwrap();
+        let instruments = Instruments::load(&path, instrument);
+        drop(fs::remove_file(path));
+        instruments
+    }
+
+    #[test]
+    fn general_midi() {
+        let gm = Instruments::gm();
+
+        assert_eq!(gm.program(false, 0, None), Some("Acoustic Grand Piano"));
+        assert_eq!(gm.program(false, 127, Some(5)), Some("Gunshot"));
+        assert_eq!(gm.program(true, 25, None), Some("TR-808 Kit"));
+        assert_eq!(gm.program(true, 1, Some(XG_DRUMS)), Some("Standard Kit 2"));
+        assert_eq!(gm.program(true, 1, None), None);
+        assert_eq!(gm.programs(false).len(), 128);
+    }
+
+    #[test]
+    fn instrument_definitions() {
+        let first = load(None).unwrap();
+        assert_eq!(first.program(false, 1, None), Some("Bright Piano"));
+        assert_eq!(first.program(false, 1, Some(8)), Some("Bright Piano 2"));
+        assert_eq!(first.program(false, 0, Some(8)), Some("Piano"));
+        assert_eq!(first.program(false, 2, Some(3)), None);
+        // The drums are looked up in the same banks
+        assert_eq!(first.program(true, 2, Some(8)), Some("Honky-tonk"));
+
+        let second = load(Some("synth b")).unwrap();
+        assert_eq!(second.program(false, 2, None), Some("Honky-tonk"));
+        assert_eq!(second.programs(false).len(), 3);
+
+        assert_eq!(load(Some("Synth C")).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,62 @@
+use sfml::window::{MouseButton, VideoMode, WindowStyle, ContextSettings, event::Event};
+use sfml::graphics::{RenderWindow, RenderTarget, Color, View, FloatRect};
+
+use {MusicBox, Screen};
+
+/// The SFML window
+pub struct Window {
+    window: RenderWindow,
+}
+
+impl Window {
+    pub fn new(view: (f32, f32)) -> Self {
+        let mut context_settings = ContextSettings::default();
+        context_settings.0.antialiasing_level = 8;
+
+        let mut window = RenderWindow::new(
+            VideoMode::new_init(view.0 as u32, view.1 as u32, 32),
+            "Virtual Midi Janko Keyboard",
+            WindowStyle::default(),
+            &context_settings,
+        ).expect("Cannot create a new Render Window.");
+        window.set_key_repeat_enabled(false);
+
+        Window {
+            window: window,
+        }
+    }
+}
+
+impl Screen for Window {
+    fn poll(&mut self, the_box: &mut MusicBox) -> bool {
+        loop {
+            let event = self.window.poll_event();
+            match event {
+                Event::Closed => return false,
+                Event::Resized {width: w, height: h} => {
+                    self.window.set_view(&View::new_from_rect(&FloatRect::new(0.0, 0.0, w as f32, h as f32)).unwrap());
+                    the_box.resize(w as f32, h as f32);
+                },
+                Event::KeyPressed {code, ctrl, ..} => the_box.press(code.into(), ctrl),
+                Event::TextEntered {code} => the_box.text(code),
+                Event::KeyReleased {code, ..} => the_box.release(code.into()),
+                Event::MouseButtonPressed {button: MouseButton::Left, x, y} => the_box.mouse_press(x, y),
+                Event::MouseMoved {x, y} => the_box.mouse_move(x, y),
+                Event::MouseButtonReleased {button: MouseButton::Left, ..} => the_box.mouse_release(),
+                Event::MouseLeft => the_box.mouse_release(),
+                Event::LostFocus => {
+                    the_box.release_keys();
+                    the_box.mouse_release()
+                },
+                Event::NoEvent => return true,
+                _ => (),
+            }
+        }
+    }
+
+    fn draw(&mut self, the_box: &MusicBox) {
+        self.window.clear(&Color::new_rgb(0x21, 0x21, 0x21));
+        self.window.draw(the_box);
+        self.window.display();
+    }
+}
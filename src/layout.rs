@@ -1,9 +1,42 @@
-use sfml::window::Key;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
 
+use key::Key;
+
+// Keys that can't be written as a single character in a layout file
+const KEY_NAMES: &[(&str, Key)] = &[
+    ("Unknown", Key::Unknown),
+    ("Tilde", Key::Tilde),
+    ("Dash", Key::Dash),
+    ("Equal", Key::Equal),
+    ("BackSpace", Key::BackSpace),
+    ("Tab", Key::Tab),
+    ("LBracket", Key::LBracket),
+    ("RBracket", Key::RBracket),
+    ("BackSlash", Key::BackSlash),
+    ("SemiColon", Key::SemiColon),
+    ("Quote", Key::Quote),
+    ("Comma", Key::Comma),
+    ("Period", Key::Period),
+    ("Slash", Key::Slash),
+    ("LShift", Key::LShift),
+    ("RShift", Key::RShift),
+    ("LControl", Key::LControl),
+    ("RControl", Key::RControl),
+    ("LAlt", Key::LAlt),
+    ("RAlt", Key::RAlt),
+    ("LSystem", Key::LSystem),
+    ("RSystem", Key::RSystem),
+    ("Menu", Key::Menu),
+];
+
+/// Keys with their offsets from the base note and keyboard rows, counting from the top
 #[derive(Debug)]
-pub struct Layout(Vec<(Key, u8)>);
+pub struct Layout(Vec<(Key, u8, u8)>);
 
 impl Layout {
+    /// The built-in QWERTY layout
     pub fn new() -> Self {
         let mut map = Vec::new();
 
@@ -16,6 +49,7 @@ impl Layout {
         map.push((Key::RShift, 22));
         map.push((Key::BackSlash, 23));
         map.push((Key::RControl, 24));
+        let lower = map.len();
 
         map.extend(
             b"q2w3e4r5t6y7u8i9o0p-[=]".into_iter()
@@ -24,16 +58,115 @@ impl Layout {
         );
         map.push((Key::BackSpace, 23));
 
+        // Odd offsets are on the upper row of each pair
+        let map = map.into_iter().enumerate()
+            .map(|(i, (k, n))| {
+                let pair = if i < lower { 2 } else { 0 };
+                (k, n, pair + 1 - n % 2)
+            })
+            .collect();
+
         Layout(map)
     }
 
-    pub fn note(&self, base: u8, key: Key) -> Option<u8> {
-        self.0.iter().find(|&&(k, _)| k == key)
-            .map(|&(_, n)| base + n)
+    /// Loads a layout from a file with a `key offset row` entry on each line.
+    ///
+    /// A key is either a single character (`z`, `2`, `=`) or a name like `RShift`,
+    /// the offset is the number of semitones above the base note and the row (0 to 3,
+    /// from the top) is used for velocity accents. Lines starting with `#` are comments.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let mut map = Vec::new();
+
+        for (num, line) in file.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue }
+
+            let invalid = |what: &str| io::Error::new(
+                io::ErrorKind::InvalidData, format!("line {}: {}", num + 1, what)
+            );
+
+            let mut words = line.split_whitespace();
+            let key = match words.next().and_then(key_by_name) {
+                Some(k) => k,
+                None => return Err(invalid("unknown key")),
+            };
+            let offset = match words.next().and_then(|w| w.parse::<u8>().ok()) {
+                Some(n) if n < 128 => n,
+                _ => return Err(invalid("offset must be a number of semitones")),
+            };
+            let row = match words.next().map(|w| w.parse::<u8>()) {
+                Some(Ok(r)) if r < 4 => r,
+                _ => return Err(invalid("row must be a number from 0 to 3")),
+            };
+
+            map.retain(|&(k, _, _)| k != key);
+            map.push((key, offset, row));
+        }
+
+        if map.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no keys in the layout"))
+        }
+
+        Ok(Layout(map))
+    }
+
+    /// The note of a key, `None` if the key isn't in the layout or the note is out of
+    /// the midi range
+    pub fn note(&self, base: i32, key: Key) -> Option<u8> {
+        self.0.iter().find(|&&(k, _, _)| k == key)
+            .map(|&(_, n, _)| base + n as i32)
+            .and_then(|n| if (0..128).contains(&n) { Some(n as u8) } else { None })
+    }
+
+    /// Short names of the keys as (row, offset, name)
+    pub fn labels(&self) -> Vec<(u8, u8, String)> {
+        self.0.iter().map(|&(k, n, r)| (r, n, key_label(k))).collect()
     }
+
+    /// The largest offset in the layout
+    pub fn span(&self) -> u8 {
+        self.0.iter().map(|&(_, n, _)| n).max().unwrap_or(0)
+    }
+
+    pub fn row(&self, key: Key) -> u8 {
+        self.0.iter().find(|&&(k, _, _)| k == key)
+            .map(|&(_, _, r)| r).unwrap_or(0)
+    }
+}
+
+fn key_by_name(name: &str) -> Option<Key> {
+    let name = name.trim_matches('"');
+    if name.len() == 1 {
+        return match into_key(name.as_bytes()[0].to_ascii_lowercase()) {
+            Key::Unknown => None,
+            k => Some(k),
+        }
+    }
+
+    KEY_NAMES.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|&(_, k)| k)
 }
 
-fn into_key(c: u8) -> Key {
+fn key_label(key: Key) -> String {
+    let printable = b"abcdefghijklmnopqrstuvwxyz0123456789`-=[]\\;',./";
+    if let Some(&b) = printable.iter().find(|&&b| into_key(b) == key) {
+        return (b as char).to_string()
+    }
+
+    match key {
+        Key::Unknown => String::new(),
+        Key::BackSpace => "Bksp".to_string(),
+        Key::LShift | Key::RShift => "Shift".to_string(),
+        Key::LControl | Key::RControl => "Ctrl".to_string(),
+        k => KEY_NAMES.iter().find(|&&(_, nk)| nk == k)
+            .map(|&(n, _)| n.to_string()).unwrap_or_default(),
+    }
+}
+
+/// The key of an ASCII character on a QWERTY keyboard
+pub fn into_key(c: u8) -> Key {
     match c {
         0x00 => Key::Unknown,
         0x08 => Key::BackSpace,
@@ -108,4 +241,65 @@ fn into_key(c: u8) -> Key {
         0x7F => Key::Delete,
         _ => Key::Unknown,
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    fn load(name: &str, text: &str) -> io::Result<Layout> {
+        let path = env::temp_dir().join(format!("vmjk-{}-{}.txt", process::id(), name));
+        fs::write(&path, text).unwrap();
+        let layout = Layout::load(&path);
+        drop(fs::remove_file(path));
+        layout
+    }
+
+    #[test]
+    fn built_in() {
+        let layout = Layout::new();
+
+        assert_eq!(layout.note(60, Key::Z), Some(62));
+        assert_eq!(layout.note(60, Key::Q), Some(60));
+        assert_eq!(layout.note(60, Key::BackSpace), Some(83));
+        assert_eq!(layout.note(120, Key::BackSpace), None);
+        assert_eq!(layout.note(60, Key::Space), None);
+        assert_eq!((layout.row(Key::Z), layout.row(Key::A), layout.row(Key::W), layout.row(Key::Num2)), (3, 2, 1, 0));
+        assert_eq!(layout.span(), 24);
+    }
+
+    #[test]
+    fn shipped_files() {
+        let qwerty = Layout::load("layouts/qwerty.txt").unwrap();
+        let built_in = Layout::new();
+        for &(key, _, _) in &built_in.0 {
+            assert_eq!(qwerty.note(60, key), built_in.note(60, key), "{:?}", key);
+            assert_eq!(qwerty.row(key), built_in.row(key), "{:?}", key);
+        }
+
+        assert!(Layout::load("layouts/dvorak.txt").is_ok());
+    }
+
+    #[test]
+    fn keys_by_character_and_name() {
+        let layout = load("keys", "# comment\n\n= 21 0\nRShift 22 2\n, 5 1\nz 2 3\nZ 4 3\n").unwrap();
+
+        assert_eq!(layout.note(60, Key::Equal), Some(81));
+        assert_eq!(layout.note(60, Key::RShift), Some(82));
+        assert_eq!(layout.note(60, Key::Comma), Some(65));
+        // The later line wins
+        assert_eq!(layout.note(60, Key::Z), Some(64));
+        assert_eq!(layout.row(Key::Equal), 0);
+        assert_eq!(layout.labels().len(), 4);
+    }
+
+    #[test]
+    fn bad_lines() {
+        for text in &["", "# only a comment\n", "z 2\n", "z 2 4\n", "z = 2 1\n", "z 128 0\n", "Nope 2 0\n", "z two 0\n"] {
+            assert_eq!(load("bad", text).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData), "{:?}", text);
+        }
+    }
+}
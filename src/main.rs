@@ -1,127 +1,812 @@
 extern crate getopts;
 extern crate portmidi;
+extern crate libc;
+#[cfg(feature = "gui")]
 extern crate sfml;
 
-use portmidi::{PortMidi, MidiMessage, OutputPort, Result as PmResult};
-use sfml::window::{Key, VideoMode, WindowStyle, ContextSettings, event::Event};
-use sfml::graphics::{Drawable, RenderWindow, RenderTarget, RenderStates, Color, View, FloatRect, Text, Font};
+use portmidi::{PortMidi, MidiMessage, InputPort, OutputPort, Result as PmResult};
+use error::{Error, Result, log};
+#[cfg(feature = "gui")]
+use sfml::graphics::{Drawable, RenderTarget, RenderStates, Color, Text, Font};
+#[cfg(feature = "gui")]
+use sfml::graphics::{CircleShape, Shape, Transformable};
+
+use key::Key;
 
 use layout::*;
 use ui::*;
 use cmd::*;
+use velocity::*;
+use record::*;
+use player::*;
+use tuning::*;
+use voices::*;
+use arp::*;
+use midi::*;
+use output::*;
+use synth::*;
+use gm::*;
+use session::*;
+use random::Random;
+
+use std::time::{Duration, Instant};
 
 mod layout;
 mod ui;
 mod cmd;
+mod velocity;
+mod random;
+mod smf;
+mod record;
+mod player;
+mod tuning;
+mod voices;
+mod arp;
+mod midi;
+mod output;
+mod synth;
+mod error;
+mod gm;
+mod session;
+mod key;
+#[cfg(feature = "gui")]
+mod gui;
+mod tui;
 
+#[cfg(feature = "gui")]
 pub static FONT: &'static [u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/PTM55F.ttf"));
 
+/// What holds a note down
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Key(Key),
+    Mouse,
+    Arp,
+}
+
+/// A note that is on, with the channel and the port it was sent to. The note sent
+/// differs from the played one when a tuning is used or the note glides in MPE mode.
+/// In chord mode every tone is held separately with the root that was played.
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    src: Source,
+    root: u8,
+    note: u8,
+    sent: u8,
+    chan: u8,
+    port: i32,
+    /// When the note on is played, a bit ahead for the arpeggiator
+    since: Instant,
+    pressure: u8,
+    timbre: u8,
+}
+
+/// Pitch bend range of the MPE member channels in semitones
+const MPE_BEND_RANGE: u8 = 48;
+
+/// Milliseconds a note has to be held in MPE mode to reach the full pressure
+const PRESSURE_RAMP: u64 = 2000;
+
+/// How often the input is polled between the frames
+const POLL: u64 = 2;
+
+/// How early the arpeggiator notes are queued for the midi thread, in milliseconds
+const LEAD: u64 = 5;
+
+/// How long an error is shown in the status line, in seconds
+const ERROR_TIME: u64 = 5;
+
+/// How far the wheels held by the arrow keys move every frame
+const BEND_STEP: i32 = 2048;
+const MOD_STEP: i32 = 4;
+
 struct MusicBox<'a> {
     cmd: Commander<'a>,
     view: (f32, f32),
     pub hexes: Hexes,
+    #[cfg(feature = "gui")]
     pub font: Box<Font>,
     font_size: u32,
     map: Layout,
-    port: OutputPort,
+    /// Where the layout was loaded from, `None` is the default one
+    layout_path: Option<String>,
+    port: Box<dyn Output>,
+    /// Where the internal synth plays, `-` is the standard output
+    synth_path: Option<String>,
+    /// The internal synth while another port is used, it goes on writing its file
+    synth: Option<Box<dyn Output>>,
+    input: Option<InputPort>,
+    thru: bool,
     chan: u8,
+    velocity: Velocity,
+    /// Humanizes the velocity and picks the notes of the random arpeggio
+    random: Random,
     patches: [(Option<u8>, Option<u16>); 16],
+    /// Controllers set with the `cc` command on each channel, with their values
+    controls: Vec<Vec<(u8, u8)>>,
     low: bool,
+    transpose: i32,
+    sustain: bool,
+    /// Whether Left Shift holds the pedal down, not Tab or a key typed in the command line
+    shift_pedal: bool,
+    local_sustain: bool,
+    held: Vec<Held>,
+    sustained: Vec<Held>,
+    recorder: Recorder,
+    record_path: Option<String>,
+    player: Option<Player>,
+    tuning: Option<Tuning>,
+    voices: Voices,
+    /// Pitch bend range of the synth in cents
+    bend_range: u16,
+    mpe: bool,
+    /// Name and intervals of the chord played by every key
+    chord: Option<(String, Vec<i32>)>,
+    arp: Arp,
+    /// Notes held for the arpeggiator with their sources and roots
+    arp_keys: Vec<(Source, u8, u8)>,
+    wheels: Wheels,
+    dragging: Option<Wheel>,
+    /// Whether the mouse button is down on the keyboard, also between the hexagons
+    mouse_down: bool,
+    bend_key: i32,
+    mod_key: i32,
+    /// Time of the messages being written, `None` is now
+    at: Option<Instant>,
     cmd_mode: bool,
+    /// The last error with the time it happened
+    error: Option<(String, Instant)>,
 }
 
 impl<'a> MusicBox<'a> {
-    fn new(midi: &'a PortMidi, port: OutputPort, view: (f32, f32), font_size: u32) -> Self {
-        let font = Box::new(Font::new_from_memory(FONT).unwrap());
+    fn new(midi: &'a dyn Devices, port: Box<dyn Output>, map: Layout, view: (f32, f32), font_size: u32) -> Self {
+        let mut hexes = Hexes::new(1.5 * font_size as f32);
+        hexes.set_keys(map.labels());
 
         MusicBox {
             cmd: Commander::new(midi),
             view: view,
-            hexes: Hexes::new(1.5 * font_size as f32),
-            font: font,
+            hexes: hexes,
+            #[cfg(feature = "gui")]
+            font: Box::new(Font::new_from_memory(FONT).unwrap()),
             font_size: font_size,
-            map: Layout::new(),
+            map: map,
+            layout_path: None,
             port: port,
+            synth_path: None,
+            synth: None,
+            input: None,
+            thru: false,
             chan: 0,
+            velocity: Velocity::new(),
+            random: Random::new(),
             patches: [(None, None); 16],
+            controls: vec![vec![]; 16],
             low: false,
+            transpose: 0,
+            sustain: false,
+            shift_pedal: false,
+            local_sustain: false,
+            held: vec![],
+            sustained: vec![],
+            recorder: Recorder::new(),
+            record_path: None,
+            player: None,
+            tuning: None,
+            voices: Voices::new(1, 15),
+            bend_range: 200,
+            mpe: false,
+            chord: None,
+            arp: Arp::new(),
+            arp_keys: vec![],
+            wheels: Wheels::new(),
+            dragging: None,
+            mouse_down: false,
+            bend_key: 0,
+            mod_key: 0,
+            at: None,
             cmd_mode: false,
+            error: None,
         }
     }
 
-    fn note_on(&mut self, note: u8) -> PmResult<()> {
-        let msg = MidiMessage {
-            status: 0x90 + self.chan,
-            data1: note,
-            data2: 64,
-        };
+    /// Shows an error in the status line and logs it, unless it's the same as the last one
+    fn report(&mut self, e: &Error) {
+        let msg = e.to_string();
+        if self.error.as_ref().map(|(m, _)| m) != Some(&msg) {
+            log(e)
+        }
+        self.error = Some((msg, Instant::now()));
+    }
+
+    fn write(&mut self, msg: MidiMessage) -> Result<()> {
+        let time = self.at.unwrap_or_else(Instant::now);
+        self.recorder.record(time, msg);
 
-        self.port.write_message(msg)
+        let result = self.port.write_at(time, msg);
+        if let Err(ref e) = result { self.report(e) }
+        result
     }
 
-    fn note_off(&mut self, note: u8) -> PmResult<()> {
+    fn send(&mut self, status: u8, data1: u8, data2: u8) -> Result<()> {
+        let chan = self.chan;
+        self.send_to(chan, status, data1, data2)
+    }
+
+    fn send_to(&mut self, chan: u8, status: u8, data1: u8, data2: u8) -> Result<()> {
         let msg = MidiMessage {
-            status: 0x80 + self.chan,
-            data1: note,
-            data2: 64,
+            status: status + chan,
+            data1: data1,
+            data2: data2,
         };
 
-        self.port.write_message(msg)
+        self.write(msg)
+    }
+
+    fn note_on(&mut self, chan: u8, note: u8, velocity: u8) -> Result<()> {
+        self.send_to(chan, 0x90, note, velocity)
+    }
+
+    fn note_off(&mut self, chan: u8, note: u8) -> Result<()> {
+        self.send_to(chan, 0x80, note, 64)
+    }
+
+    fn port_id(&self) -> i32 {
+        self.port.id()
     }
 
     fn all_notes_off(&mut self) {
         // Better to send ALL NOTES OFF, but there're some synths that don't understand it
-        for n in 0..36 {
-            drop(self.note_off(48 + n))
+        let chan = self.chan;
+        for n in 0..128 {
+            drop(self.note_off(chan, n))
         }
 
+        self.flush();
         self.hexes.release_all();
     }
 
-    fn set_patch(&mut self, patch: u8) {
-        let msg = MidiMessage {
-            status: 0xC0 + self.chan,
-            data1: patch,
-            data2: 0,
+    /// Turns off the held and the sustained notes on the current port, for example before
+    /// it's replaced. The notes are still shown until the keys are released.
+    fn flush(&mut self) {
+        let port = self.port_id();
+        let notes: Vec<_> = self.held.iter_mut().chain(self.sustained.iter_mut())
+            .filter(|h| h.port == port)
+            .map(|h| { h.port = -1; (h.chan, h.sent, h.since) })
+            .collect();
+
+        // An arpeggiated note on can still be queued, its note off has to go after it
+        let (at, now) = (self.at, Instant::now());
+        for (chan, note, since) in notes {
+            self.at = if since > now { Some(since) } else { at };
+            drop(self.note_off(chan, note))
+        }
+        self.at = at;
+        self.sustained.clear();
+        self.hexes.release_sustained();
+    }
+
+    /// The note of the first key of the layout
+    fn base(&self) -> i32 {
+        60 + self.transpose - if self.low { 12 } else { 0 }
+    }
+
+    fn set_transpose(&mut self, transpose: i32) {
+        self.transpose = transpose.clamp(-60, 60);
+
+        let base = self.base();
+        self.hexes.base_note(base);
+    }
+
+    /// Sends the sustain pedal state. With local sustain the note offs of released keys
+    /// are held back until the pedal is up, for synths that ignore CC#64.
+    fn pedal(&mut self, down: bool) {
+        if self.sustain == down { return }
+
+        self.sustain = down;
+        drop(self.control_change(64, if down { 127 } else { 0 }));
+
+        if !down {
+            let notes = ::std::mem::take(&mut self.sustained);
+            let port = self.port_id();
+            if self.local_sustain {
+                for h in notes.into_iter().filter(|h| h.port == port) {
+                    drop(self.note_off(h.chan, h.sent))
+                }
+            }
+            self.hexes.release_sustained();
+        }
+    }
+
+    fn control_change(&mut self, cc: u8, value: u8) -> Result<()> {
+        self.send(0xB0, cc, value)
+    }
+
+    /// Sends a 14-bit pitch bend, 8192 is the center
+    fn pitch_bend(&mut self, chan: u8, value: i32) -> Result<()> {
+        let value = value.clamp(0, 16383);
+        self.send_to(chan, 0xE0, (value & 0x7F) as u8, (value >> 7) as u8)
+    }
+
+    /// Sends a registered parameter and resets the parameter number
+    fn rpn(&mut self, chan: u8, param: u8, msb: u8, lsb: Option<u8>) -> Result<()> {
+        self.send_to(chan, 0xB0, 101, 0)?;
+        self.send_to(chan, 0xB0, 100, param)?;
+        self.send_to(chan, 0xB0, 6, msb)?;
+        if let Some(lsb) = lsb {
+            self.send_to(chan, 0xB0, 38, lsb)?;
+        }
+        self.send_to(chan, 0xB0, 101, 127)?;
+        self.send_to(chan, 0xB0, 100, 127)
+    }
+
+    /// Pitch bend that moves a note by the given number of cents
+    fn bend(&self, cents: i32) -> i32 {
+        let range = if self.mpe { 100 * MPE_BEND_RANGE as i32 } else { self.bend_range as i32 };
+        8192 + cents * 8192 / range
+    }
+
+    /// Pitch of a key in cents above the note 0
+    fn pitch(&self, note: u8) -> Option<i32> {
+        match self.tuning {
+            Some(ref tuning) => tuning.note(note).map(|(n, cents)| 100 * n as i32 + cents),
+            None => Some(100 * note as i32),
+        }
+    }
+
+    /// Configures the lower MPE zone with the manager on channel 0 and the given number of
+    /// member channels above it, `None` turns the zone off
+    fn set_mpe(&mut self, members: Option<u8>) {
+        let was = self.mpe;
+        drop(self.rpn(0, 6, members.unwrap_or(0), None));
+
+        match members {
+            Some(n) => {
+                self.voices = Voices::new(1, n);
+                self.chan = 0;
+                self.mpe = true;
+            },
+            None => {
+                // Glides leave the member channels bent
+                if was {
+                    let (lo, hi) = self.voices.range();
+                    for chan in lo..hi + 1 {
+                        drop(self.pitch_bend(chan, 8192))
+                    }
+                }
+                self.mpe = false;
+            },
+        }
+    }
+
+    /// Sends the MPE zone and the bend ranges of the manager and the member channels again,
+    /// to a new port
+    fn resend_mpe(&mut self) {
+        let (_, members) = self.voices.range();
+        let (semis, cents) = ((self.bend_range / 100) as u8, (self.bend_range % 100) as u8);
+
+        drop(self.rpn(0, 6, members, None));
+        drop(self.rpn(0, 0, semis, Some(cents)));
+        for chan in 1..members + 1 {
+            drop(self.rpn(chan, 0, MPE_BEND_RANGE, Some(0)))
+        }
+    }
+
+    /// Sets the pitch bend range of the synth with RPN 0
+    fn set_bend_range(&mut self, semis: u8, cents: u8) {
+        let chan = self.chan;
+        drop(self.rpn(chan, 0, semis, Some(cents)));
+
+        // Retuned notes are bent on their own channels
+        if self.tuning.is_some() && !self.mpe {
+            let (lo, hi) = self.voices.range();
+            for c in (lo..hi + 1).filter(|&c| c != chan) {
+                drop(self.rpn(c, 0, semis, Some(cents)))
+            }
+        }
+        self.bend_range = 100 * semis as u16 + cents as u16;
+    }
+
+    /// Pitch bend of a retuned note with the bend strip added
+    fn tuned_bend(&self, cents: i32) -> i32 {
+        self.bend(cents) + self.wheels.bend() - 8192
+    }
+
+    fn set_bend(&mut self, value: i32) {
+        self.wheels.set_bend(value);
+        let (chan, bend) = (self.chan, self.wheels.bend());
+        drop(self.pitch_bend(chan, bend));
+
+        // Retuned notes are bent on their own channels, the strip moves them along
+        if self.mpe { return }
+        let port = self.port_id();
+        let bends: Vec<_> = match self.tuning {
+            Some(ref tuning) => self.held.iter().chain(self.sustained.iter())
+                .filter(|h| h.port == port)
+                .filter_map(|h| tuning.note(h.note).map(|(_, cents)| (h.chan, cents)))
+                .collect(),
+            None => return,
         };
+        for (chan, cents) in bends {
+            let bend = self.tuned_bend(cents);
+            drop(self.pitch_bend(chan, bend))
+        }
+    }
+
+    fn set_modulation(&mut self, value: u8) {
+        self.wheels.set_modulation(value);
+        drop(self.control_change(1, value))
+    }
+
+    /// Moves the wheels held by the arrow keys, the bend springs back when it's let go
+    fn update_wheels(&mut self) {
+        if self.dragging != Some(Wheel::Bend) {
+            let bend = self.wheels.bend();
+            let target = match self.bend_key {
+                1 => 16383,
+                -1 => 0,
+                _ => 8192,
+            };
+            if bend != target {
+                self.set_bend(bend + (target - bend).clamp(-BEND_STEP, BEND_STEP));
+            }
+        }
+
+        if self.mod_key != 0 {
+            let modulation = self.wheels.modulation();
+            let value = (modulation as i32 + self.mod_key * MOD_STEP).clamp(0, 127) as u8;
+            if value != modulation { self.set_modulation(value) }
+        }
+    }
+
+    /// Pressure of the notes held in MPE mode grows with the time they are held
+    fn update_pressure(&mut self) {
+        if !self.mpe { return }
+
+        let port = self.port_id();
+        for i in 0..self.held.len() {
+            let held = self.held[i];
+            let time = held.since.elapsed();
+            let ms = time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1000000;
+            let pressure = (ms * 127 / PRESSURE_RAMP).min(127) as u8;
+
+            if pressure != held.pressure && held.port == port {
+                self.held[i].pressure = pressure;
+                drop(self.send_to(held.chan, 0xD0, pressure, 0))
+            }
+        }
+    }
 
-        drop(self.port.write_message(msg));
+    /// Queues the arpeggiator notes that are due soon at their exact times
+    fn tick_arp(&mut self) {
+        let now = Instant::now();
+        let soon = now + Duration::from_millis(LEAD);
+        while let Some(time) = self.arp.deadline() {
+            if time > soon { break }
+
+            // Late notes are played now
+            let time = time.max(now);
+            self.at = Some(time);
+            let (end, start) = self.arp.tick(time, &mut self.random);
+            if end { self.end_note(Source::Arp) }
+            if let Some((note, velocity)) = start {
+                self.start_tone(Source::Arp, note, note, velocity, 64)
+            }
+        }
+        self.at = None;
+    }
+
+    /// When the arpeggiator has to be ticked next
+    fn deadline(&self) -> Option<Instant> {
+        self.arp.deadline().map(|d| d - Duration::from_millis(LEAD))
+    }
+
+    fn set_arp(&mut self, pattern: Option<Pattern>) {
+        if pattern.is_none() {
+            for (_, _, note) in self.arp_keys.drain(..) {
+                self.hexes.release(note)
+            }
+        }
+        self.arp.set_pattern(pattern);
+    }
+
+    fn set_tuning(&mut self, tuning: Option<Tuning>) {
+        self.hexes.set_cents(tuning.as_ref().map(|t| t.deviations()));
+
+        // Channels of the old tuning are left centered
+        if self.tuning.is_some() {
+            let (lo, hi) = self.voices.range();
+            for chan in lo..hi + 1 {
+                drop(self.pitch_bend(chan, 8192))
+            }
+        }
+        self.tuning = tuning;
+    }
+
+    fn set_patch(&mut self, patch: u8) {
+        drop(self.send(0xC0, patch, 0));
         self.patches[self.chan as usize].0 = Some(patch);
     }
 
     fn set_bank(&mut self, bank: u16) {
         let (msb, lsb) = ((bank >> 7) as u8, (bank & 0x7F) as u8);
-        let msg = MidiMessage {
-            status: 0xD0 + self.chan,
-            data1: 0,
-            data2: msb,
+
+        drop(self.control_change(0x00, msb));
+        drop(self.control_change(0x20, lsb));
+        self.patches[self.chan as usize].1 = Some(bank);
+    }
+
+    /// Bank select only takes effect on the next program change, so the program is resent
+    /// after the bank if it is known.
+    fn select(&mut self, patch: Option<u8>, bank: Option<u16>) {
+        if let Some(b) = bank { self.set_bank(b) }
+
+        let patch = patch.or(self.patches[self.chan as usize].0);
+        if let Some(p) = patch { self.set_patch(p) }
+    }
+
+    /// Reads the midi input, lighting up its notes and forwarding them in thru mode
+    fn poll_input(&mut self) {
+        let events = match self.input {
+            Some(ref input) => match input.read_n(1024) {
+                Ok(Some(events)) => events,
+                Ok(None) => return,
+                Err(e) => return self.report(&e.into()),
+            },
+            None => return,
         };
 
-        drop(self.port.write_message(msg));
+        for ev in events {
+            let msg = ev.message;
+            match (msg.status & 0xF0, msg.data2) {
+                (0x90, 0) | (0x80, _) => self.hexes.remote_release(msg.data1),
+                (0x90, _) => self.hexes.remote_press(msg.data1),
+                _ => (),
+            }
 
-        let msg = MidiMessage {
-            status: 0xD0 + self.chan,
-            data1: 0x20,
-            data2: lsb,
+            // Sysex can't be forwarded one message at a time
+            if self.thru && (msg.status < 0xF0 || msg.status >= 0xF8) {
+                drop(self.write(msg))
+            }
+        }
+    }
+
+    /// Called between the frames to handle the midi input, the arpeggiator and the playback
+    fn update(&mut self) {
+        self.poll_input();
+        self.tick_arp();
+        self.update_pressure();
+
+        let base = self.base();
+        let (chan, low, high) = (self.chan, base.max(0) as u8, (base + self.map.span() as i32).min(127) as u8);
+        let (due, expected) = match self.player {
+            Some(ref mut player) => {
+                player.set_playable(chan, low, high);
+                let due = player.tick();
+                let expected = if player.is_waiting() { Some(player.expected().to_vec()) } else { None };
+                (due, expected)
+            },
+            None => return,
         };
 
-        drop(self.port.write_message(msg));
-        self.patches[self.chan as usize].1 = Some(bank);
+        for msg in due {
+            match (msg.status & 0xF0, msg.data2) {
+                (0x90, 0) | (0x80, _) => self.hexes.guide_release(msg.data1),
+                (0x90, _) => self.hexes.guide_press(msg.data1),
+                _ => (),
+            }
+            drop(self.write(msg))
+        }
+
+        if let Some(notes) = expected {
+            self.hexes.set_guide(&notes)
+        }
     }
 
-    fn execute(&mut self, act: Action) {
+    /// Sends the note offs left by pausing or seeking the playback
+    fn silence(&mut self, offs: Vec<MidiMessage>) {
+        for msg in offs {
+            drop(self.write(msg))
+        }
+        self.hexes.set_guide(&[]);
+    }
+
+    fn execute(&mut self, act: Action) -> Result<()> {
         match act {
             Action::Device(dev) => {
-                if let Ok(p) = self.cmd.midi.output_port(dev, 1024) {
-                    self.port = p
-                }
+                let port = self.cmd.midi.open_output(&dev)?;
+                self.set_port(port)
+            },
+            Action::Internal if self.port.id() == INTERNAL => (),
+            Action::Internal => {
+                let synth: Box<dyn Output> = match (self.synth.take(), self.synth_path.clone()) {
+                    (Some(synth), _) => synth,
+                    (None, Some(path)) => match Synth::new(&path) {
+                        Ok(synth) => Box::new(synth),
+                        Err(e) => return Err(Error::File(path, e)),
+                    },
+                    (None, None) => return Err(Error::NoSynth),
+                };
+                self.set_port(synth)
             },
+            Action::Input(dev) => {
+                let p = self.cmd.midi.open_input(&dev)?;
+                self.hexes.release_remote();
+                self.input = Some(p)
+            },
+            Action::Thru => self.thru = !self.thru,
             Action::Chan(n) => self.chan = n,
-            Action::Patch(patch, bank) => {
-                if let Some(b) = bank { self.set_bank(b) }
-                if let Some(p) = patch { self.set_patch(p) }
+            Action::Transpose(n) => self.set_transpose(n),
+            Action::Patch(patch, bank) => self.select(patch, bank),
+            Action::Control(cc, value) => {
+                self.control_change(cc, value)?;
+                let controls = &mut self.controls[self.chan as usize];
+                controls.retain(|&(c, _)| c != cc);
+                controls.push((cc, value));
+            },
+            Action::Velocity(value) => self.velocity.set(value),
+            Action::Curve(curve) => self.velocity.set_curve(curve),
+            Action::Sustain(local) => self.local_sustain = local,
+            Action::Record => self.recorder.start(),
+            Action::Stop => {
+                self.recorder.stop();
+                let offs = self.player.as_mut().map(|p| { p.pause(); p.seek(0) });
+                if let Some(offs) = offs { self.silence(offs) }
+            },
+            Action::Save(path, format) => match path.or(self.record_path.clone()) {
+                Some(path) => self.recorder.save(&path, format).map_err(|e| Error::File(path, e))?,
+                None => return Err(Error::Usage(cmd::usage("save"))),
+            },
+            Action::SaveSession(path) => self.session().save(&path).map_err(|e| Error::File(path, e))?,
+            Action::LoadSession(path) => match Session::load(&path) {
+                Ok(session) => self.restore(session)?,
+                Err(e) => return Err(Error::File(path, e)),
+            },
+            Action::Play(Some(path)) => match Player::load(&path) {
+                Ok(mut player) => {
+                    if let Some(offs) = self.player.as_mut().map(|p| p.pause()) {
+                        self.silence(offs)
+                    }
+                    if let Some(ref p) = self.player { drop(player.set_wait(p.is_waiting())) }
+                    player.play();
+                    self.player = Some(player);
+                },
+                Err(e) => return Err(Error::File(path, e)),
+            },
+            Action::Play(None) => if let Some(ref mut p) = self.player { p.play() },
+            Action::Pause => {
+                let offs = self.player.as_mut().map(|p| p.pause());
+                if let Some(offs) = offs { self.silence(offs) }
+            },
+            Action::Seek(secs) => {
+                let offs = self.player.as_mut().map(|p| p.seek(secs.saturating_mul(1000)));
+                if let Some(offs) = offs { self.silence(offs) }
+            },
+            Action::Tempo(percent) => if let Some(ref mut p) = self.player { p.set_tempo(percent) },
+            Action::Wait => {
+                let offs = self.player.as_mut().map(|p| {
+                    let wait = !p.is_waiting();
+                    p.set_wait(wait)
+                });
+                if let Some(offs) = offs { self.silence(offs) }
+            },
+            Action::Instruments(None) => self.cmd.instruments = Instruments::gm(),
+            Action::Instruments(Some((path, name))) => match Instruments::load(&path, name.as_deref()) {
+                Ok(instruments) => self.cmd.instruments = instruments,
+                Err(e) => return Err(Error::File(path, e)),
+            },
+            Action::Layout(None) => {
+                self.set_layout(Layout::new());
+                self.layout_path = None
+            },
+            Action::Layout(Some(path)) => match Layout::load(&path) {
+                Ok(map) => {
+                    self.set_layout(map);
+                    self.layout_path = Some(path)
+                },
+                Err(e) => return Err(Error::File(path, e)),
+            },
+            Action::Tuning(None) => self.set_tuning(None),
+            Action::Tuning(Some((scl, kbm))) => match Tuning::load(&scl, kbm.as_ref()) {
+                Ok(tuning) => self.set_tuning(Some(tuning)),
+                Err(e) => return Err(Error::File(scl, e)),
+            },
+            Action::TuningChans(lo, hi) => self.voices = Voices::new(lo, hi),
+            Action::Mpe(members) => self.set_mpe(members),
+            Action::BendRange(semis, cents) => self.set_bend_range(semis, cents),
+            Action::Chord(chord) => self.chord = chord,
+            Action::Arp(pattern) => self.set_arp(pattern),
+            Action::ArpRate(bpm, per_beat) => self.arp.set_rate(bpm, per_beat),
+            Action::ArpGate(percent) => self.arp.set_gate(percent),
+            Action::ArpOctaves(n) => self.arp.set_octaves(n),
+            Action::Labels(Some(spelling)) => self.hexes.set_spelling(spelling),
+            Action::Labels(None) => self.hexes.toggle_key_labels(),
+        }
+
+        Ok(())
+    }
+
+    /// Turns off the notes on the old port, also the ones of the playback, before
+    /// replacing it
+    fn set_port(&mut self, port: Box<dyn Output>) {
+        self.flush();
+        let offs = self.player.as_mut().map(|p| p.notes_off());
+        if let Some(offs) = offs { self.silence(offs) }
+
+        let old = ::std::mem::replace(&mut self.port, port);
+        if old.id() == INTERNAL { self.synth = Some(old) }
+
+        if self.mpe { self.resend_mpe() }
+    }
+
+    /// The state saved in a session file
+    fn session(&self) -> Session {
+        let mut session = Session::new();
+        session.device = match self.port.id() {
+            INTERNAL => Some("internal".to_string()),
+            NOWHERE => None,
+            _ => Some(self.port.name()),
+        };
+        session.channel = self.chan;
+        session.transpose = self.transpose;
+        session.low = self.low;
+        session.layout = self.layout_path.clone();
+        session.velocity = self.velocity.value();
+        session.curve = self.velocity.curve().clone();
+
+        for (c, channel) in session.channels.iter_mut().enumerate() {
+            channel.program = self.patches[c].0;
+            channel.bank = self.patches[c].1;
+            channel.controls = self.controls[c].clone();
+        }
+
+        session
+    }
+
+    /// Applies a session, then resends the bank, the program and the controllers of every
+    /// channel to the output. A missing device or layout doesn't stop the rest.
+    fn restore(&mut self, session: Session) -> Result<()> {
+        let device = match session.device {
+            Some(ref name) if name == "internal" => self.execute(Action::Internal),
+            Some(ref name) if self.port.id() >= 0 && *name == self.port.name() => Ok(()),
+            Some(name) => {
+                let dev = self.cmd.midi.list().into_iter().find(|d| d.output && d.name == name);
+                match dev {
+                    Some(dev) => self.execute(Action::Device(dev)),
+                    None => Err(Error::NoDevice(name)),
+                }
+            },
+            None => Ok(()),
+        };
+        let layout = match session.layout {
+            Some(path) => self.execute(Action::Layout(Some(path))),
+            None => Ok(()),
+        };
+
+        self.velocity.set(session.velocity);
+        self.velocity.set_curve(session.curve);
+        self.low = session.low;
+        self.set_transpose(session.transpose);
+
+        for (c, channel) in session.channels.into_iter().enumerate() {
+            self.chan = c as u8;
+            if channel.program.is_some() || channel.bank.is_some() {
+                self.select(channel.program, channel.bank)
+            }
+            for &(cc, value) in &channel.controls {
+                drop(self.control_change(cc, value))
             }
+            self.controls[c] = channel.controls;
         }
+        self.chan = session.channel;
+        self.cmd.drums = self.chan == 9;
+
+        device.and(layout)
+    }
+
+    fn set_layout(&mut self, map: Layout) {
+        self.hexes.set_keys(map.labels());
+        self.map = map;
     }
 
     fn text(&mut self, ch: char) {
@@ -134,10 +819,24 @@ impl<'a> MusicBox<'a> {
         if self.cmd_mode == true {
             match key {
                 Key::Escape => { self.cmd_mode = false },
-                Key::Return => if let Some(act) = self.cmd.emit() {
-                    self.execute(act);
-                    self.cmd_mode = false
+                Key::Return => {
+                    let result = match self.cmd.emit() {
+                        Ok(Some(act)) => self.execute(act),
+                        Ok(None) => Ok(()),
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(()) => self.cmd_mode = false,
+                        // The command line stays open to try again
+                        Err(e) => {
+                            self.report(&e);
+                            self.cmd.show_error(e.to_string())
+                        },
+                    }
                 },
+                Key::Tab => self.cmd.complete(),
+                Key::Up => self.cmd.up(),
+                Key::Down => self.cmd.down(),
                 _ => (),
             }
             return
@@ -148,12 +847,29 @@ impl<'a> MusicBox<'a> {
                 self.chan += 1,
             Key::Subtract if self.chan != 0 => 
                 self.chan -= 1,
+            Key::LShift => {
+                self.shift_pedal = true;
+                self.pedal(true)
+            },
+            Key::Tab => {
+                let down = !self.sustain;
+                self.pedal(down)
+            },
+            Key::Multiply => self.velocity.up(),
+            Key::Divide => self.velocity.down(),
             Key::Space => {
                 if !self.low { self.low = true } else { self.low = false };
 
-                let shift = if self.low == false { 0 } else { 12 };
-                self.hexes.base_note(60 - shift);
-                self.all_notes_off();
+                let transpose = self.transpose;
+                self.set_transpose(transpose);
+            },
+            Key::Home => {
+                let transpose = self.transpose + if ctrl { 1 } else { 12 };
+                self.set_transpose(transpose);
+            },
+            Key::End => {
+                let transpose = self.transpose - if ctrl { 1 } else { 12 };
+                self.set_transpose(transpose);
             },
             Key::PageUp if !ctrl => {
                 let num = self.patches[self.chan as usize].0
@@ -168,58 +884,366 @@ impl<'a> MusicBox<'a> {
             Key::PageUp => {
                 let num = self.patches[self.chan as usize].1
                     .map(|n| if n != 16383 { n + 1 } else { n }).unwrap_or(0);
-                self.set_bank(num);
+                self.select(None, Some(num));
             },
             Key::PageDown => {
                 let num = self.patches[self.chan as usize].1
                     .map(|n| if n != 0 { n - 1 } else { n }).unwrap_or(0);
-                self.set_bank(num);
+                self.select(None, Some(num));
             },
             Key::Return => {
                 self.all_notes_off();
             },
             Key::Escape => {
+                self.cmd.drums = self.chan == 9;
                 self.cmd_mode = true
             },
+            Key::F1 => self.hexes.toggle_labels(),
+            Key::Up => self.bend_key = 1,
+            Key::Down => self.bend_key = -1,
+            Key::Right => self.mod_key = 1,
+            Key::Left => self.mod_key = -1,
+
+            _ => if let Some(note) = self.map.note(self.base(), key) {
+                let src = Source::Key(key);
+                if self.held.iter().any(|h| h.src == src) || self.arp_keys.iter().any(|k| k.0 == src) {
+                    return
+                }
+
+                let velocity = self.velocity.note(self.map.row(key), &mut self.random);
+                self.start_note(Source::Key(key), note, velocity, 64);
+            }
+        }
+    }
+
+    /// Starts a note or in chord mode the chord rooted on it. The timbre is only
+    /// sent in MPE mode.
+    fn start_note(&mut self, src: Source, root: u8, velocity: u8, timbre: u8) {
+        let notes: Vec<u8> = match self.chord {
+            Some((_, ref intervals)) => intervals.iter().map(|i| root as i32 + i)
+                .filter(|n| (0..128).contains(n)).map(|n| n as u8).collect(),
+            None => vec![root],
+        };
 
-            _ => if let Some(note) = self.map.note(60, key) {
-                let note = note - if self.low == false { 0 } else { 12 };
-                drop(self.note_on(note));
+        for note in notes {
+            if self.arp.is_on() {
+                self.arp.press(note, velocity);
+                self.arp_keys.push((src, root, note));
                 self.hexes.press(note);
+            } else {
+                self.start_tone(src, root, note, velocity, timbre)
+            }
+        }
+    }
+
+    fn start_tone(&mut self, src: Source, root: u8, note: u8, velocity: u8, timbre: u8) {
+        if let Some(ref mut p) = self.player { p.key(note) }
+
+        let port = self.port_id();
+        let (sent, cents) = match self.tuning {
+            Some(ref tuning) => match tuning.note(note) {
+                Some((sent, cents)) => (sent, Some(cents)),
+                None => return,
+            },
+            None => (note, None),
+        };
+        let chan = if self.tuning.is_some() || self.mpe {
+            let busy: Vec<_> = self.held.iter().chain(self.sustained.iter())
+                .filter(|h| h.port == port).map(|h| h.chan).collect();
+            self.voices.allocate(&busy)
+        } else { self.chan };
+
+        if self.local_sustain {
+            if let Some(i) = self.sustained.iter().position(|h| (h.chan, h.sent, h.port) == (chan, sent, port)) {
+                self.sustained.remove(i);
+                drop(self.note_off(chan, sent));
+            }
+        }
+
+        if self.mpe {
+            // The member channel could be left bent or pressed by its previous note
+            let bend = self.bend(cents.unwrap_or(0));
+            drop(self.pitch_bend(chan, bend));
+            drop(self.send_to(chan, 0xB0, 74, timbre));
+            drop(self.send_to(chan, 0xD0, 0, 0));
+        } else if let Some(cents) = cents {
+            let bend = self.tuned_bend(cents);
+            drop(self.pitch_bend(chan, bend))
+        }
+        drop(self.note_on(chan, sent, velocity));
+        self.held.push(Held {
+            src: src,
+            root: root,
+            note: note,
+            sent: sent,
+            chan: chan,
+            port: port,
+            since: self.at.unwrap_or_else(Instant::now),
+            pressure: 0,
+            timbre: timbre,
+        });
+        self.hexes.press(note);
+    }
+
+    /// Ends every note started by the source, all the tones of a chord. A tone shared
+    /// with the chord of another key is only ended by the last one released.
+    fn end_note(&mut self, src: Source) {
+        while let Some(i) = self.held.iter().position(|h| h.src == src) {
+            let held = self.held.remove(i);
+
+            // The note could be turned off already if the port was changed
+            let sounding = held.port == self.port_id();
+            let shown = self.held.iter().any(|h| h.note == held.note) || self.arp.holds(held.note);
+            let shared = self.held.iter().any(|h| (h.chan, h.sent, h.port) == (held.chan, held.sent, held.port));
+
+            if shared {
+                continue
+            } else if self.sustain && sounding {
+                if !self.local_sustain { drop(self.note_off(held.chan, held.sent)) }
+                self.sustained.push(held);
+                if !shown { self.hexes.sustain(held.note) }
+            } else {
+                if sounding { drop(self.note_off(held.chan, held.sent)) }
+                if !shown { self.hexes.release(held.note) }
+            }
+        }
+
+        while let Some(i) = self.arp_keys.iter().position(|k| k.0 == src) {
+            let (_, _, note) = self.arp_keys.remove(i);
+            self.arp.release(note);
+            if !self.arp.holds(note) && !self.held.iter().any(|h| h.note == note) {
+                self.hexes.release(note)
             }
         }
     }
 
+    fn mouse_press(&mut self, x: i32, y: i32) {
+        if let Some(wheel) = self.wheels.at(x as f32, y as f32) {
+            self.dragging = Some(wheel);
+            return self.drag(wheel, y)
+        }
+
+        self.mouse_down = true;
+        self.mouse_note(x, y)
+    }
+
+    /// Plays the hexagon under the mouse, the higher it's clicked the louder it sounds
+    fn mouse_note(&mut self, x: i32, y: i32) {
+        if let Some((note, pos)) = self.hexes.hex_at(x as f32, y as f32) {
+            let velocity = 127 - (pos * 126.0) as u8;
+            self.start_note(Source::Mouse, note, velocity, timbre(pos));
+        }
+    }
+
+    fn mouse_move(&mut self, x: i32, y: i32) {
+        if let Some(wheel) = self.dragging {
+            return self.drag(wheel, y)
+        }
+
+        if !self.mouse_down { return }
+
+        let held = self.held.iter().find(|h| h.src == Source::Mouse).map(|h| h.root);
+        let root = held.or(self.arp_keys.iter().find(|k| k.0 == Source::Mouse).map(|k| k.1));
+        let hex = self.hexes.hex_at(x as f32, y as f32);
+        match root {
+            // Coming from a gap between the hexagons
+            None => self.mouse_note(x, y),
+            Some(_) if self.mpe => if let Some((note, pos)) = hex {
+                for i in 0..self.held.len() {
+                    if self.held[i].src == Source::Mouse { self.glide(i, note, pos) }
+                }
+            },
+            Some(root) => if hex.map(|(n, _)| n) != Some(root) {
+                self.end_note(Source::Mouse);
+                self.mouse_note(x, y);
+            },
+        }
+    }
+
+    /// Bends a held note in MPE mode along with its root to another hexagon and sets
+    /// its timbre from the vertical position in the hexagon
+    fn glide(&mut self, i: usize, root: u8, pos: f32) {
+        let held = self.held[i];
+        if held.port != self.port_id() { return }
+
+        let timbre = timbre(pos);
+        if timbre != held.timbre {
+            self.held[i].timbre = timbre;
+            drop(self.send_to(held.chan, 0xB0, 74, timbre));
+        }
+
+        if root == held.root { return }
+        let note = held.note as i32 + root as i32 - held.root as i32;
+        if !(0..=127).contains(&note) { return }
+        let note = note as u8;
+        let pitch = match self.pitch(note) {
+            Some(pitch) => pitch,
+            None => return,
+        };
+
+        let bend = self.bend(pitch - 100 * held.sent as i32);
+        drop(self.pitch_bend(held.chan, bend));
+        self.held[i].root = root;
+        self.held[i].note = note;
+
+        if !self.held.iter().any(|h| h.note == held.note) { self.hexes.release(held.note) }
+        self.hexes.press(note);
+        if let Some(ref mut p) = self.player { p.key(note) }
+    }
+
+    fn mouse_release(&mut self) {
+        self.dragging = None;
+        self.mouse_down = false;
+        self.end_note(Source::Mouse);
+    }
+
+    fn drag(&mut self, wheel: Wheel, y: i32) {
+        let (bend, modulation) = (self.wheels.bend(), self.wheels.modulation());
+        self.wheels.drag(wheel, y as f32);
+
+        if self.wheels.bend() != bend {
+            let value = self.wheels.bend();
+            self.set_bend(value)
+        }
+        if self.wheels.modulation() != modulation {
+            let value = self.wheels.modulation();
+            self.set_modulation(value)
+        }
+    }
+
     fn release(&mut self, key: Key) {
-        if let Some(note) = self.map.note(60, key) {
-            let note =  note - if self.low == false { 0 } else { 12 };
-            drop(self.note_off(note));
-            self.hexes.release(note);
+        match key {
+            Key::LShift => if self.shift_pedal {
+                self.shift_pedal = false;
+                self.pedal(false)
+            },
+            Key::Up | Key::Down => self.bend_key = 0,
+            Key::Left | Key::Right => self.mod_key = 0,
+            _ => self.end_note(Source::Key(key)),
         }
     }
 
     fn status(&self) -> String {
-        let level = if self.low { "Low" } else { "High" };
-        let patch = self.patches[self.chan as usize].0.map(|p| p.to_string()).unwrap_or("?".to_string());
+        let status = self.state();
+        match self.error {
+            Some((ref e, time)) if time.elapsed() < Duration::from_secs(ERROR_TIME) => format!(" {} |{}", e, status),
+            _ => status,
+        }
+    }
+
+    fn state(&self) -> String {
+        let base = self.base();
+        let (low, high) = (base.max(0), (base + self.map.span() as i32).min(127));
+        let level = format!("{}–{}{}", note_name(low as u8), note_name(high as u8),
+            if self.sustain { ", Sustain" } else { "" });
+        let (patch, bank) = self.patches[self.chan as usize];
+        let patch = match patch {
+            Some(p) => match self.cmd.instruments.program(self.chan == 9, p, bank) {
+                Some(name) => format!("{} {}", p, name),
+                None => p.to_string(),
+            },
+            None => "?".to_string(),
+        };
         let bank = self.patches[self.chan as usize].1.map(|p| p.to_string()).unwrap_or("?".to_string());
-        let dev = self.port.device().name().to_string();
+        let dev = match self.input {
+            Some(ref input) => format!("{} {} {}",
+                input.device().name(), if self.thru { "→" } else { "|" }, self.port.name()),
+            None => self.port.name(),
+        };
+
+        let playback = match self.player {
+            Some(ref p) if p.is_playing() => {
+                let (pos, len) = p.position();
+                let ((pm, ps), (lm, ls)) = ((pos / 60, pos % 60), (len / 60, len % 60));
+                let mode = if p.is_waiting() { "waiting" } else { "playing" };
+                format!(", {} {}:{:02}/{}:{:02}", mode, pm, ps, lm, ls)
+            },
+            _ => String::new(),
+        };
 
-        format!(" [{}], channel {}, program {} from bank {} [{}]", level, self.chan, patch, bank, dev)
+        let tuning = match self.tuning {
+            Some(ref t) => {
+                let (lo, hi) = self.voices.range();
+                format!(", tuning {} on channels {}–{}", t.name(), lo, hi)
+            },
+            None => String::new(),
+        };
+
+        let chord = match self.chord {
+            Some((ref name, _)) => format!(", chord {}", name),
+            None => String::new(),
+        };
+        let arp = if self.arp.is_on() { format!(", {}", self.arp) } else { String::new() };
+        let mpe = if self.mpe {
+            format!(", MPE on channels 1–{}", self.voices.range().1)
+        } else { String::new() };
+
+        format!(" [{}], channel {}, program {} from bank {}, {}{}{}{}{}{} [{}]",
+            level, self.chan, patch, bank, self.velocity, chord, arp, mpe, tuning, playback, dev)
+    }
+
+    /// Saves the recording started with `--record` when vmjk is closed
+    /// Ends the notes of the keys held down, whose releases don't come when the window
+    /// loses the focus
+    fn release_keys(&mut self) {
+        let mut keys = vec![Key::LShift, Key::Up, Key::Left];
+        for src in self.held.iter().map(|h| h.src).chain(self.arp_keys.iter().map(|k| k.0)) {
+            if let Source::Key(key) = src {
+                if !keys.contains(&key) { keys.push(key) }
+            }
+        }
+
+        for key in keys {
+            self.release(key)
+        }
+    }
+
+    /// Turns off everything that still sounds and saves the recording
+    fn finish(&mut self) {
+        self.release_keys();
+        self.mouse_release();
+        self.set_arp(None);
+        self.end_note(Source::Arp);
+        self.pedal(false);
+        self.flush();
+        let offs = self.player.as_mut().map(|p| p.pause());
+        if let Some(offs) = offs { self.silence(offs) }
+
+        if let Some(path) = self.record_path.take() {
+            self.recorder.stop();
+            if let Err(e) = self.recorder.save(&path, 0) {
+                println!("{}: {}", path, e)
+            }
+        }
     }
 
     fn resize(&mut self, w: f32, h: f32) {
         self.view = (w, h);
-        self.hexes.resize(w as f32, h as f32 - 1.5 * self.font_size as f32);
+
+        // The wheels take the right edge
+        let (margin, strip) = (1.5 * self.font_size as f32, 2.0 * self.font_size as f32);
+        self.hexes.resize(w - strip - 0.5 * margin, h - margin);
+        self.wheels.resize(w - strip, margin, strip - 0.25 * margin, h - 1.25 * margin);
     }
 }
 
+#[cfg(feature = "gui")]
 impl<'a> Drawable for MusicBox<'a> {
     fn draw<RT: RenderTarget>(&self, target: &mut RT, rs: &mut RenderStates) {
         let status = self.status();
         let text = Text::new_init(&status, &self.font, self.font_size).unwrap();
 
         text.draw(target, rs);
+        if self.recorder.is_recording() {
+            let r = 0.3 * self.font_size as f32;
+            let mut dot = CircleShape::new_init(r, 16).unwrap();
+            dot.set_fill_color(&Color::new_rgb(0xE5, 0x39, 0x35));
+            dot.move2f(text.get_local_bounds().width + 2.0 * r, 0.6 * self.font_size as f32 - r);
+            dot.draw(target, rs);
+        }
         self.hexes.draw(target, rs);
+        self.wheels.draw(target, rs);
+        Labels::new(&self.hexes, &self.font).draw(target, rs);
 
         if self.cmd_mode {
             let frame = CmdFrame::new(self.cmd.text(), self.view, &self.font, self.font_size);
@@ -229,43 +1253,75 @@ impl<'a> Drawable for MusicBox<'a> {
 }
 
 
-fn proceed(midi: PortMidi, port: OutputPort) {
-    let view = (980.0, 310.0);
-    let mut the_box = MusicBox::new(&midi, port, view, 20);
-    
-    let mut context_settings = ContextSettings::default();
-    context_settings.0.antialiasing_level = 8;
+/// Where the keyboard is shown and played: the SFML window or the terminal
+trait Screen {
+    /// Handles the pending input, false when vmjk should quit
+    fn poll(&mut self, the_box: &mut MusicBox) -> bool;
+
+    fn draw(&mut self, the_box: &MusicBox);
+}
+
+/// What vmjk starts with, collected from the command line
+struct Startup {
+    port: Box<dyn Output>,
+    synth_path: Option<String>,
+    input: Option<InputPort>,
+    thru: bool,
+    map: Layout,
+    layout_path: Option<String>,
+    record: Option<String>,
+    mpe: Option<u8>,
+    session: Option<Session>,
+    view: (f32, f32),
+}
+
+fn proceed<S: Screen>(midi: PortMidi, startup: Startup, mut screen: S) {
+    let view = startup.view;
+    let mut the_box = MusicBox::new(&midi, startup.port, startup.map, view, 20);
+    the_box.layout_path = startup.layout_path;
+    the_box.resize(view.0, view.1);
+    if let Some(path) = history_path() {
+        the_box.cmd.keep_history(path)
+    }
+    the_box.input = startup.input;
+    the_box.synth_path = startup.synth_path;
+    the_box.thru = startup.thru;
+    if startup.mpe.is_some() {
+        the_box.set_mpe(startup.mpe);
+    }
+    if startup.record.is_some() {
+        the_box.recorder.start();
+        the_box.record_path = startup.record;
+    }
+    if let Some(session) = startup.session {
+        if let Err(e) = the_box.restore(session) { the_box.report(&e) }
+    }
 
-    let mut window = RenderWindow::new(
-        VideoMode::new_init(view.0 as u32, view.1 as u32, 32),
-        "Virtual Midi Janko Keyboard",
-        WindowStyle::default(),
-        &context_settings,
-    ).expect("Cannot create a new Render Window.");
-    window.set_key_repeat_enabled(false);
+    let frame = Duration::from_millis(25);
+    let mut next_frame = Instant::now();
 
+    // The input is handled every few milliseconds, the screen is only drawn every frame
     loop {
-        loop {
-            let event = window.poll_event();
-            match event {
-                Event::Closed => return,
-                Event::Resized {width: w, height: h} => {
-                    window.set_view(&View::new_from_rect(&FloatRect::new(0.0, 0.0, w as f32, h as f32)).unwrap());
-                    the_box.resize(w as f32, h as f32);
-                },
-                Event::KeyPressed {code, ctrl, ..} => the_box.press(code, ctrl),
-                Event::TextEntered {code} => the_box.text(code),
-                Event::KeyReleased {code, ..} => the_box.release(code),
-                Event::NoEvent => break,
-                _ => (),
-            }
+        if !screen.poll(&mut the_box) {
+            return the_box.finish()
         }
 
-        window.clear(&Color::new_rgb(0x21, 0x21, 0x21));
-        window.draw(&the_box);
+        the_box.update();
+
+        let now = Instant::now();
+        if now >= next_frame {
+            the_box.update_wheels();
+            screen.draw(&the_box);
 
-        window.display();
-        ::std::thread::sleep(::std::time::Duration::from_millis(25));
+            // Skip the frames missed by a slow draw
+            next_frame += frame;
+            if now > next_frame { next_frame = now + frame }
+        }
+
+        let now = Instant::now();
+        let mut wake = next_frame.min(now + Duration::from_millis(POLL));
+        if let Some(d) = the_box.deadline() { wake = wake.min(d) }
+        if wake > now { ::std::thread::sleep(wake - now) }
     }
 }
 
@@ -285,6 +1341,8 @@ fn usage(prog: &str, opts: getopts::Options) {
 fn main() {
     use getopts::*;
     use std::env::*;
+    use std::fs::File;
+    use std::io::stdout;
 
     let midi = PortMidi::new().unwrap();
     let port_id;
@@ -296,6 +1354,16 @@ fn main() {
     opts.optflag("h", "help", "show this help message");
     opts.optflag("l", "list", "list midi ports available");
     opts.optopt("p", "port", "connect to midi port [id]", "id");
+    opts.optopt("i", "input", "read notes from midi port [id]", "id");
+    opts.optflag("t", "thru", "forward the input port to the output");
+    opts.optopt("r", "record", "record everything played to a midi file", "file");
+    opts.optopt("", "layout", "load the keyboard layout from a file", "file");
+    opts.optopt("", "session", "restore the device, the channels and the keyboard from a session file", "file");
+    opts.optflagopt("", "dump", "write the midi messages as text to a file or the standard output", "file");
+    opts.optopt("", "synth", "play with the internal synth into a WAV file or `-` for raw samples \
+                              on the standard output", "file");
+    opts.optflagopt("", "mpe", "play in MPE mode with the number of member channels (15)", "n");
+    opts.optflag("", "tui", "show the keyboard in the terminal instead of a window");
 
     let matches = opts.parse(args).unwrap();
     if matches.opt_present("h") {
@@ -307,6 +1375,9 @@ fn main() {
         for d in devs.iter().filter(|d| d.is_output()) {
             println!("{}: {}", d.id(), d.name());
         }
+        for d in devs.iter().filter(|d| d.is_input()) {
+            println!("{}: {} (input)", d.id(), d.name());
+        }
         return
     }
     if let Some(id) = matches.opt_str("p") {
@@ -318,15 +1389,266 @@ fn main() {
         }
     } else { port_id = None }
 
-    let port = if midi.device_count() != 0 {
-        match get_port(&midi, port_id) {
-            Ok(p) => p,
+    let input = match matches.opt_str("i").map(|id| id.parse::<i32>()) {
+        Some(Ok(id)) => match midi.device(id).and_then(|dev| midi.input_port(dev, 1024)) {
+            Ok(p) => Some(p),
             Err(e) => {
                 println!("{}", e);
                 return
             }
+        },
+        Some(Err(_)) => {
+            println!("Not an integer: {}", matches.opt_str("i").unwrap());
+            return
+        },
+        None => None,
+    };
+
+    let mpe = match matches.opt_str("mpe").map(|n| n.parse::<u8>()) {
+        Some(Ok(n)) if (1..=15).contains(&n) => Some(n),
+        Some(_) => {
+            println!("Not a number of channels: {}", matches.opt_str("mpe").unwrap());
+            return
+        },
+        None if matches.opt_present("mpe") => Some(15),
+        None => None,
+    };
+
+    let layout_path = matches.opt_str("layout");
+    let map = match layout_path {
+        Some(ref path) => match Layout::load(path) {
+            Ok(map) => map,
+            Err(e) => {
+                println!("{}: {}", path, e);
+                return
+            }
+        },
+        None => Layout::new(),
+    };
+
+    let session = match matches.opt_str("session") {
+        Some(path) => match Session::load(&path) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                println!("{}: {}", path, e);
+                return
+            }
+        },
+        None => None,
+    };
+
+    let synth_path = matches.opt_str("synth");
+    let port: Box<dyn Output> = if let Some(ref path) = synth_path {
+        match Synth::new(path) {
+            Ok(synth) => Box::new(synth),
+            Err(e) => {
+                println!("{}: {}", path, e);
+                return
+            }
+        }
+    } else if matches.opt_present("dump") {
+        match matches.opt_str("dump") {
+            Some(path) => match File::create(&path) {
+                Ok(file) => Box::new(Dump::new(Box::new(file), &path)),
+                Err(e) => {
+                    println!("{}: {}", path, e);
+                    return
+                }
+            },
+            None => Box::new(Dump::new(Box::new(stdout()), "stdout")),
         }
-    } else { panic!("No midi devices in the system") };
+    } else {
+        // Machines with only midi inputs run too
+        match get_port(&midi, port_id) {
+            Ok(p) => Box::new(MidiOut::new(p)),
+            Err(e) if port_id.is_some() => {
+                println!("{}", e);
+                return
+            },
+            Err(_) => {
+                println!("No midi output devices in the system, use --synth or --dump to hear or see the notes");
+                Box::new(Null)
+            },
+        }
+    };
+
+    let startup = Startup {
+        port: port,
+        synth_path: synth_path,
+        input: input,
+        thru: matches.opt_present("t"),
+        map: map,
+        layout_path: layout_path,
+        record: matches.opt_str("r"),
+        mpe: mpe,
+        session: session,
+        view: (980.0, 310.0),
+    };
+
+    #[cfg(feature = "gui")]
+    {
+        if !matches.opt_present("tui") {
+            let window = gui::Window::new(startup.view);
+            return proceed(midi, startup, window)
+        }
+    }
+
+    match tui::Terminal::new() {
+        Ok(term) => proceed(midi, startup, term),
+        Err(e) => println!("/dev/tty: {}", e),
+    }
+}
 
-    proceed(midi, port);
+/// Timbre of an MPE note from the vertical position in its hexagon, brighter at the top
+fn timbre(pos: f32) -> u8 {
+    127 - (pos.clamp(0.0, 1.0) * 127.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use portmidi::InputPort;
+
+    use super::*;
+
+    struct NoDevices;
+
+    impl Devices for NoDevices {
+        fn list(&self) -> Vec<Device> {
+            vec![]
+        }
+
+        fn open_output(&self, dev: &Device) -> Result<Box<dyn Output>> {
+            Err(Error::NoDevice(dev.name.clone()))
+        }
+
+        fn open_input(&self, dev: &Device) -> Result<InputPort> {
+            Err(Error::NoDevice(dev.name.clone()))
+        }
+    }
+
+    /// The dump output, shared with the test
+    #[derive(Clone)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        /// The bytes of the messages dumped so far
+        fn messages(&self) -> Vec<(u8, u8, u8)> {
+            let text = String::from_utf8(self.0.borrow().clone()).unwrap();
+            text.lines()
+                .map(|l| {
+                    let bytes: Vec<u8> = l.split_whitespace().skip(1).take(3)
+                        .map(|b| u8::from_str_radix(b, 16).unwrap()).collect();
+                    (bytes[0], bytes[1], bytes[2])
+                })
+                .collect()
+        }
+    }
+
+    fn music_box<'a>(devices: &'a NoDevices) -> (MusicBox<'a>, Buffer) {
+        let buffer = Buffer(Rc::new(RefCell::new(vec![])));
+        let port = Box::new(Dump::new(Box::new(buffer.clone()), "test"));
+        (MusicBox::new(devices, port, Layout::new(), (980.0, 310.0), 20), buffer)
+    }
+
+    /// Whether every note on is turned off later on its channel
+    fn paired(msgs: &[(u8, u8, u8)]) -> bool {
+        let mut on = vec![];
+        for &(status, note, velocity) in msgs {
+            let chan = status & 0x0F;
+            match status & 0xF0 {
+                0x90 if velocity > 0 => on.push((chan, note)),
+                0x80 | 0x90 => on.retain(|&n| n != (chan, note)),
+                _ => (),
+            }
+        }
+        on.is_empty()
+    }
+
+    #[test]
+    fn notes_are_turned_off() {
+        let devices = NoDevices;
+        let (mut the_box, buffer) = music_box(&devices);
+
+        the_box.press(Key::Z, false);
+        the_box.press(Key::Z, false);
+        the_box.press(Key::X, false);
+        // Transposing while the keys are held doesn't change their note offs
+        the_box.press(Key::Home, false);
+        the_box.release(Key::Z);
+        the_box.release(Key::X);
+
+        the_box.execute(Action::Chord(Some(("maj".to_string(), vec![0, 4, 7])))).unwrap();
+        the_box.execute(Action::Chan(3)).unwrap();
+        the_box.press(Key::A, false);
+        the_box.execute(Action::Chord(None)).unwrap();
+        the_box.release(Key::A);
+
+        let msgs = buffer.messages();
+        assert_eq!(msgs.iter().filter(|m| m.0 & 0xF0 == 0x90).count(), 5);
+        assert_eq!(msgs[0], (0x90, 62, 64));
+        assert!(msgs.contains(&(0x93, 77, 64)));
+        assert!(paired(&msgs));
+    }
+
+    #[test]
+    fn all_notes_off_turns_off_held_notes() {
+        let devices = NoDevices;
+        let (mut the_box, buffer) = music_box(&devices);
+
+        the_box.press(Key::S, false);
+        the_box.press(Key::Return, false);
+        the_box.release(Key::S);
+
+        let msgs = buffer.messages();
+        assert!(paired(&msgs));
+        // Turned off by all notes off and for the held key, not again on the release
+        assert_eq!(msgs.iter().filter(|m| *m == &(0x80, 63, 64)).count(), 2);
+    }
+
+    #[test]
+    fn shared_chord_tones_end_with_the_last_key() {
+        let devices = NoDevices;
+        let (mut the_box, buffer) = music_box(&devices);
+
+        // D major and F# major share the F#
+        the_box.execute(Action::Chord(Some(("maj".to_string(), vec![0, 4, 7])))).unwrap();
+        the_box.press(Key::Z, false);
+        the_box.press(Key::C, false);
+        the_box.release(Key::Z);
+        assert!(!buffer.messages().contains(&(0x80, 66, 64)));
+
+        the_box.release(Key::C);
+        let msgs = buffer.messages();
+        assert_eq!(msgs.iter().filter(|m| *m == &(0x80, 66, 64)).count(), 1);
+        assert!(paired(&msgs));
+    }
+
+    #[test]
+    fn bank_select_comes_before_the_program() {
+        let devices = NoDevices;
+        let (mut the_box, buffer) = music_box(&devices);
+
+        the_box.execute(Action::Chan(9)).unwrap();
+        the_box.execute(Action::Patch(Some(5), Some(200))).unwrap();
+        assert_eq!(buffer.messages(), vec![(0xB9, 0x00, 1), (0xB9, 0x20, 72), (0xC9, 5, 0)]);
+
+        // A new bank resends the program
+        the_box.execute(Action::Patch(None, Some(0))).unwrap();
+        assert_eq!(&buffer.messages()[3..], &[(0xB9, 0x00, 0), (0xB9, 0x20, 0), (0xC9, 5, 0)]);
+    }
 }
@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::mem;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use portmidi::{PortMidi, MidiMessage, InputPort, OutputPort, DeviceInfo};
+
+use libc::{c_char, c_int};
+
+use error::{Error, Result};
+use output::Output;
+
+#[repr(C)]
+struct PmDeviceInfo {
+    struct_version: c_int,
+    interf: *const c_char,
+    name: *const c_char,
+    input: c_int,
+    output: c_int,
+    opened: c_int,
+}
+
+extern "C" {
+    fn Pm_GetDeviceInfo(id: c_int) -> *const PmDeviceInfo;
+}
+
+/// A midi device of the system
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub id: i32,
+    pub name: String,
+    /// Like ALSA or CoreMIDI
+    pub interface: Option<String>,
+    /// An output device, otherwise an input
+    pub output: bool,
+}
+
+/// Lists and opens the midi devices, so the keyboard can run without portmidi
+pub trait Devices {
+    fn list(&self) -> Vec<Device>;
+
+    fn open_output(&self, dev: &Device) -> Result<Box<dyn Output>>;
+
+    fn open_input(&self, dev: &Device) -> Result<InputPort>;
+}
+
+impl Devices for PortMidi {
+    fn list(&self) -> Vec<Device> {
+        self.devices().unwrap_or(vec![]).into_iter()
+            .map(|d| Device {
+                id: d.id(),
+                name: d.name().to_string(),
+                interface: interface(d.id()),
+                output: d.is_output(),
+            })
+            .collect()
+    }
+
+    fn open_output(&self, dev: &Device) -> Result<Box<dyn Output>> {
+        let port = self.device(dev.id).and_then(|d| self.output_port(d, 1024))?;
+        Ok(Box::new(MidiOut::new(port)))
+    }
+
+    fn open_input(&self, dev: &Device) -> Result<InputPort> {
+        Ok(self.device(dev.id).and_then(|d| self.input_port(d, 1024))?)
+    }
+}
+
+/// The interface of a device, which the binding doesn't give
+fn interface(id: i32) -> Option<String> {
+    unsafe {
+        let info = Pm_GetDeviceInfo(id);
+        if info.is_null() || (*info).interf.is_null() { return None }
+        CStr::from_ptr((*info).interf).to_str().ok().map(|s| s.to_string())
+    }
+}
+
+/// An output port written from its own thread, so the messages go out at their
+/// times instead of waiting for the next frame
+pub struct MidiOut {
+    tx: Sender<(Instant, MidiMessage)>,
+    errors: Receiver<Error>,
+    device: DeviceInfo,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MidiOut {
+    pub fn new(port: OutputPort) -> Self {
+        let device = port.device();
+        let (tx, rx) = channel();
+        let (err_tx, errors) = channel();
+        let thread = thread::spawn(move || run(port, rx, err_tx));
+
+        MidiOut {
+            tx: tx,
+            errors: errors,
+            device: device,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for MidiOut {
+    /// Waits for the queued messages, the last note offs would be lost on exit
+    fn drop(&mut self) {
+        drop(mem::replace(&mut self.tx, channel().0));
+        if let Some(thread) = self.thread.take() {
+            drop(thread.join())
+        }
+    }
+}
+
+impl Output for MidiOut {
+    /// Also returns the errors of the messages written before
+    fn write_at(&mut self, time: Instant, msg: MidiMessage) -> Result<()> {
+        if let Ok(e) = self.errors.try_recv() { return Err(e) }
+        self.tx.send((time, msg)).map_err(|_| Error::Disconnected(self.device.name().to_string()))
+    }
+
+    fn name(&self) -> String {
+        self.device.name().to_string()
+    }
+
+    fn id(&self) -> i32 {
+        self.device.id()
+    }
+}
+
+/// Writes the queued messages at their times until the `MidiOut` is dropped
+fn run(mut port: OutputPort, rx: Receiver<(Instant, MidiMessage)>, errors: Sender<Error>) {
+    // Messages sorted by their times, messages with the same time keep their order
+    let mut queue: VecDeque<(Instant, MidiMessage)> = VecDeque::new();
+
+    loop {
+        let next = match queue.front() {
+            Some(&(time, _)) => {
+                let now = Instant::now();
+                if time <= now { None } else {
+                    match rx.recv_timeout(time - now) {
+                        Ok(msg) => Some(msg),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => return drain(&mut port, &mut queue, &errors),
+                    }
+                }
+            },
+            None => match rx.recv() {
+                Ok(msg) => Some(msg),
+                Err(_) => return,
+            },
+        };
+
+        if let Some((time, msg)) = next {
+            let i = queue.iter().position(|&(t, _)| t > time).unwrap_or(queue.len());
+            queue.insert(i, (time, msg));
+        }
+
+        write_due(&mut port, &mut queue, &errors);
+    }
+}
+
+/// Writes the rest of the queue at its times, so the note offs queued ahead still go out
+/// after the port is replaced
+fn drain(port: &mut OutputPort, queue: &mut VecDeque<(Instant, MidiMessage)>, errors: &Sender<Error>) {
+    while let Some(&(time, _)) = queue.front() {
+        let now = Instant::now();
+        if time > now { thread::sleep(time - now) }
+        write_due(port, queue, errors);
+    }
+}
+
+fn write_due(port: &mut OutputPort, queue: &mut VecDeque<(Instant, MidiMessage)>, errors: &Sender<Error>) {
+    let now = Instant::now();
+    while queue.front().is_some_and(|&(t, _)| t <= now) {
+        let (_, msg) = queue.pop_front().unwrap();
+        if let Err(e) = port.write_message(msg) {
+            drop(errors.send(e.into()))
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::io::Write;
+use std::time::Instant;
+
+use portmidi::MidiMessage;
+
+use error::{Error, Result};
+
+/// Id of the internal synth, the midi devices have non-negative ids
+pub const INTERNAL: i32 = -2;
+
+/// Id of the null and the dump outputs
+pub const NOWHERE: i32 = -3;
+
+/// Where the midi messages go: a midi port, the internal synth or a dump
+pub trait Output {
+    /// Queues a message to be played at the given time
+    fn write_at(&mut self, time: Instant, msg: MidiMessage) -> Result<()>;
+
+    fn name(&self) -> String;
+
+    /// Tells the outputs apart, so the notes can be turned off on the right one
+    fn id(&self) -> i32;
+}
+
+/// Drops everything, used when there are no midi devices
+pub struct Null;
+
+impl Output for Null {
+    fn write_at(&mut self, _: Instant, _: MidiMessage) -> Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        "no output".to_string()
+    }
+
+    fn id(&self) -> i32 {
+        NOWHERE
+    }
+}
+
+/// Writes every message as a line of text with its time in seconds, the bytes in hex
+/// and what it is, e.g. `1.250 90 3C 40 note on`
+pub struct Dump {
+    out: Box<dyn Write>,
+    name: String,
+    start: Instant,
+}
+
+impl Dump {
+    pub fn new(out: Box<dyn Write>, name: &str) -> Self {
+        Dump {
+            out: out,
+            name: name.to_string(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Output for Dump {
+    fn write_at(&mut self, time: Instant, msg: MidiMessage) -> Result<()> {
+        let time = if time > self.start { time.duration_since(self.start) } else { Default::default() };
+        let kind = match msg.status & 0xF0 {
+            0x80 => "note off",
+            0x90 if msg.data2 == 0 => "note off",
+            0x90 => "note on",
+            0xA0 => "aftertouch",
+            0xB0 => "control",
+            0xC0 => "program",
+            0xD0 => "pressure",
+            0xE0 => "bend",
+            _ => "system",
+        };
+
+        writeln!(self.out, "{}.{:03} {:02X} {:02X} {:02X} {}", time.as_secs(), time.subsec_millis(),
+            msg.status, msg.data1, msg.data2, kind)
+            .and_then(|_| self.out.flush()).map_err(|e| Error::File(self.name.clone(), e))
+    }
+
+    fn name(&self) -> String {
+        format!("dump → {}", self.name)
+    }
+
+    fn id(&self) -> i32 {
+        NOWHERE
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::time::Instant;
+
+use portmidi::MidiMessage;
+
+use smf;
+
+/// Plays a midi file in time with the main loop
+pub struct Player {
+    events: Vec<(u32, MidiMessage)>,
+    pos: usize,
+    time: f64,
+    last: Instant,
+    playing: bool,
+    tempo: f64,
+    wait: bool,
+    expected: Vec<u8>,
+    sounding: Vec<(u8, u8)>,
+    /// The channel and the lowest and highest notes played on the keyboard, only these
+    /// notes are waited for
+    playable: (u8, u8, u8),
+}
+
+impl Player {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let events = smf::read(BufReader::new(File::open(path)?))?;
+
+        Ok(Player {
+            events: events,
+            pos: 0,
+            time: 0.0,
+            last: Instant::now(),
+            playing: false,
+            tempo: 1.0,
+            wait: false,
+            expected: vec![],
+            sounding: vec![],
+            playable: (0, 0, 127),
+        })
+    }
+
+    pub fn play(&mut self) {
+        if self.pos == self.events.len() {
+            self.seek(0);
+        }
+
+        self.last = Instant::now();
+        self.playing = true;
+    }
+
+    /// Stops the playback, returning the note offs for the notes that are still on
+    pub fn pause(&mut self) -> Vec<MidiMessage> {
+        self.playing = false;
+        self.silence()
+    }
+
+    /// Moves to the given time in milliseconds, returning the note offs for the notes
+    /// that were on
+    pub fn seek(&mut self, ms: u32) -> Vec<MidiMessage> {
+        self.time = ms as f64;
+        self.pos = self.events.iter().position(|&(t, _)| t >= ms).unwrap_or(self.events.len());
+        self.last = Instant::now();
+        self.silence()
+    }
+
+    /// Sets the playback speed in percents of the original tempo
+    pub fn set_tempo(&mut self, percent: u16) {
+        self.tempo = percent as f64 / 100.0;
+    }
+
+    /// In the waiting mode the player stops at every note until it's played on the keyboard.
+    /// Returns the note offs for the notes that are on.
+    pub fn set_wait(&mut self, wait: bool) -> Vec<MidiMessage> {
+        self.wait = wait;
+        self.silence()
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.wait
+    }
+
+    pub fn set_playable(&mut self, chan: u8, low: u8, high: u8) {
+        self.playable = (chan, low, high);
+    }
+
+    fn is_playable(&self, msg: MidiMessage) -> bool {
+        let (chan, low, high) = self.playable;
+        msg.status & 0x0F == chan && msg.data1 >= low && msg.data1 <= high
+    }
+
+    /// Notes the player is waiting for
+    pub fn expected(&self) -> &[u8] {
+        &self.expected
+    }
+
+    /// A note played on the keyboard
+    pub fn key(&mut self, note: u8) {
+        self.expected.retain(|&n| n != note);
+    }
+
+    /// Advances the playback to the current time and returns the messages that are due.
+    /// In the waiting mode the notes that can be played on the keyboard are left to the
+    /// player and are not returned.
+    pub fn tick(&mut self) -> Vec<MidiMessage> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+
+        let mut due = vec![];
+        if !self.playing || !self.expected.is_empty() { return due }
+
+        let elapsed = elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6;
+        self.time += elapsed * self.tempo;
+
+        while let Some(&(t, msg)) = self.events.get(self.pos) {
+            if t as f64 > self.time { break }
+            self.pos += 1;
+
+            let note = match (msg.status & 0xF0, msg.data2) {
+                (0x90, 0) | (0x80, _) => Some(false),
+                (0x90, _) => Some(true),
+                _ => None,
+            };
+
+            let chan = msg.status & 0x0F;
+            let waiting = self.wait && self.is_playable(msg);
+            match note {
+                Some(true) if waiting => {
+                    self.expected.push(msg.data1);
+
+                    // Wait for the whole chord
+                    let chord = self.events[self.pos..].iter()
+                        .take_while(|&&(tt, _)| tt == t)
+                        .any(|&(_, m)| m.status & 0xF0 == 0x90 && m.data2 > 0 && self.is_playable(m));
+                    if !chord { break }
+                },
+                // Unless it was played before the keyboard moved to it
+                Some(false) if waiting && !self.sounding.contains(&(chan, msg.data1)) => (),
+                Some(true) => {
+                    self.sounding.push((chan, msg.data1));
+                    due.push(msg)
+                },
+                Some(false) => {
+                    self.sounding.retain(|&n| n != (chan, msg.data1));
+                    due.push(msg)
+                },
+                None => due.push(msg),
+            }
+        }
+
+        if self.pos == self.events.len() && self.expected.is_empty() {
+            self.playing = false;
+        }
+
+        due
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Current position and the length in seconds
+    pub fn position(&self) -> (u32, u32) {
+        let length = self.events.last().map(|&(t, _)| t).unwrap_or(0);
+        (self.time as u32 / 1000, length / 1000)
+    }
+
+    fn silence(&mut self) -> Vec<MidiMessage> {
+        self.expected.clear();
+        self.notes_off()
+    }
+
+    /// The note offs for the notes that are on, which are then left to the old port when
+    /// the output is changed
+    pub fn notes_off(&mut self) -> Vec<MidiMessage> {
+        self.sounding.drain(..).map(|(chan, note)| MidiMessage {
+            status: 0x80 + chan,
+            data1: note,
+            data2: 0,
+        }).collect()
+    }
+}
@@ -0,0 +1,25 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A xorshift32 generator seeded from the clock, good enough to humanize
+#[derive(Debug)]
+pub struct Random(u32);
+
+impl Random {
+    pub fn new() -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos()).unwrap_or(0);
+
+        // Zero would stay zero
+        Random(seed | 1)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+
+        x
+    }
+}
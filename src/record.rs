@@ -0,0 +1,58 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use portmidi::MidiMessage;
+
+use smf;
+
+/// Collects the messages sent to the output with their times
+pub struct Recorder {
+    start: Instant,
+    events: Vec<(u32, MidiMessage)>,
+    recording: bool,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder {
+            start: Instant::now(),
+            events: vec![],
+            recording: false,
+        }
+    }
+
+    /// Starts a new take, dropping the previous one
+    pub fn start(&mut self) {
+        self.start = Instant::now();
+        self.events.clear();
+        self.recording = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.recording = false;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Records a message sent at the given time
+    pub fn record(&mut self, time: Instant, msg: MidiMessage) {
+        // Only channel messages go into the file
+        if !self.recording || msg.status < 0x80 || msg.status >= 0xF0 { return }
+
+        let time = if time > self.start { time.duration_since(self.start) } else { Duration::new(0, 0) };
+        let ms = time.as_secs() as u32 * 1000 + time.subsec_millis();
+
+        // Arpeggiated notes are written ahead of time, so the events are kept sorted
+        let i = self.events.iter().rposition(|&(t, _)| t <= ms).map_or(0, |i| i + 1);
+        self.events.insert(i, (ms, msg));
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: u16) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        smf::write(file, &self.events, format)
+    }
+}
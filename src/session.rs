@@ -0,0 +1,289 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use velocity::Curve;
+
+/// The program, the bank and the controllers set on a channel
+#[derive(Debug, Clone, Default)]
+pub struct Channel {
+    pub program: Option<u8>,
+    pub bank: Option<u16>,
+    /// Controller numbers with their values
+    pub controls: Vec<(u8, u8)>,
+}
+
+/// The state saved in a session file, written in a small subset of TOML:
+///
+/// ```text
+/// device = "FluidSynth"
+/// channel = 0
+/// velocity = 64
+/// curve = "human 10"
+///
+/// [channels.0]
+/// program = 17
+/// bank = 0
+/// cc.7 = 100
+/// ```
+#[derive(Debug)]
+pub struct Session {
+    /// Name of the output device, `internal` for the internal synth
+    pub device: Option<String>,
+    pub channel: u8,
+    pub transpose: i32,
+    /// Whether the keyboard is an octave lower
+    pub low: bool,
+    pub layout: Option<String>,
+    pub velocity: u8,
+    pub curve: Curve,
+    pub channels: Vec<Channel>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            device: None,
+            channel: 0,
+            transpose: 0,
+            low: false,
+            layout: None,
+            velocity: 64,
+            curve: Curve::Fixed,
+            channels: vec![Channel::default(); 16],
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let mut session = Session::new();
+        let mut chan: Option<usize> = None;
+
+        for (num, line) in file.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue }
+
+            let invalid = |what: &str| io::Error::new(
+                io::ErrorKind::InvalidData, format!("line {}: {}", num + 1, what)
+            );
+
+            if line.starts_with('[') {
+                let table = line.trim_start_matches('[').split(']').next().unwrap_or("").trim();
+                chan = match table.trim_start_matches("channels.").parse::<usize>() {
+                    Ok(c) if table.starts_with("channels.") && c < 16 => Some(c),
+                    _ => return Err(invalid("unknown table")),
+                };
+                continue
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), value(&line[i + 1..]).ok_or_else(|| invalid("invalid value"))?),
+                None => return Err(invalid("expected key = value")),
+            };
+            let bad = || invalid(&format!("invalid {}", key));
+
+            match chan {
+                None => match key {
+                    "device" => session.device = Some(value),
+                    "channel" => session.channel = number(&value, 15).ok_or_else(bad)? as u8,
+                    "transpose" => session.transpose = value.parse().ok()
+                        .filter(|t: &i32| t.abs() <= 60).ok_or_else(bad)?,
+                    "low" => session.low = value.parse().map_err(|_| bad())?,
+                    "layout" => session.layout = Some(value),
+                    "velocity" => session.velocity = number(&value, 127).filter(|&v| v > 0).ok_or_else(bad)? as u8,
+                    "curve" => session.curve = parse_curve(&value).ok_or_else(bad)?,
+                    _ => (),
+                },
+                Some(c) => {
+                    let channel = &mut session.channels[c];
+                    match key {
+                        "program" => channel.program = Some(number(&value, 127).ok_or_else(bad)? as u8),
+                        "bank" => channel.bank = Some(number(&value, 16383).ok_or_else(bad)?),
+                        cc if cc.starts_with("cc.") => {
+                            let cc = number(&cc[3..], 127).ok_or_else(bad)? as u8;
+                            let value = number(&value, 127).ok_or_else(bad)? as u8;
+                            channel.controls.retain(|&(c, _)| c != cc);
+                            channel.controls.push((cc, value));
+                        },
+                        _ => (),
+                    }
+                },
+            }
+        }
+
+        Ok(session)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = File::create(path)?;
+
+        writeln!(file, "# vmjk session")?;
+        if let Some(ref device) = self.device {
+            writeln!(file, "device = {}", quote(device))?;
+        }
+        writeln!(file, "channel = {}", self.channel)?;
+        writeln!(file, "transpose = {}", self.transpose)?;
+        writeln!(file, "low = {}", self.low)?;
+        if let Some(ref layout) = self.layout {
+            writeln!(file, "layout = {}", quote(layout))?;
+        }
+        writeln!(file, "velocity = {}", self.velocity)?;
+        writeln!(file, "curve = {}", quote(&curve_name(&self.curve)))?;
+
+        for (c, channel) in self.channels.iter().enumerate() {
+            if channel.program.is_none() && channel.bank.is_none() && channel.controls.is_empty() {
+                continue
+            }
+
+            writeln!(file, "\n[channels.{}]", c)?;
+            if let Some(program) = channel.program {
+                writeln!(file, "program = {}", program)?;
+            }
+            if let Some(bank) = channel.bank {
+                writeln!(file, "bank = {}", bank)?;
+            }
+            for &(cc, value) in &channel.controls {
+                writeln!(file, "cc.{} = {}", cc, value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A value without the quotes and the comment after it
+fn value(text: &str) -> Option<String> {
+    let text = text.trim();
+    if !text.starts_with('"') {
+        return Some(text.split('#').next().unwrap_or("").trim().to_string())
+    }
+
+    let mut value = String::new();
+    let mut chars = text[1..].chars();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn number(text: &str, max: u16) -> Option<u16> {
+    text.trim().parse().ok().filter(|&n| n <= max)
+}
+
+/// The curve as it's written in the `vel` command
+fn curve_name(curve: &Curve) -> String {
+    match *curve {
+        Curve::Fixed => "fixed".to_string(),
+        Curve::Human(range) => format!("human {}", range),
+        Curve::Accent(ref a) => format!("accent {} {} {} {}", a[0], a[1], a[2], a[3]),
+    }
+}
+
+fn parse_curve(text: &str) -> Option<Curve> {
+    let mut words = text.split_whitespace();
+    match words.next() {
+        Some("fixed") => Some(Curve::Fixed),
+        Some("human") => words.next().and_then(|r| r.parse().ok()).map(Curve::Human),
+        Some("accent") => {
+            let mut accents = [0; 4];
+            for a in accents.iter_mut() {
+                *a = words.next()?.parse().ok()?;
+            }
+            Some(Curve::Accent(accents))
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("vmjk-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut session = Session::new();
+        session.device = Some("Fluid \"Synth\"".to_string());
+        session.channel = 9;
+        session.transpose = -12;
+        session.low = true;
+        session.layout = Some("layouts/dvorak.txt".to_string());
+        session.velocity = 100;
+        session.curve = Curve::Accent([10, 0, -5, -10]);
+        session.channels[0].program = Some(17);
+        session.channels[9].bank = Some(200);
+        session.channels[9].controls = vec![(7, 100), (10, 0)];
+
+        let file = path("session.toml");
+        session.save(&file).unwrap();
+        let loaded = Session::load(&file).unwrap();
+        drop(fs::remove_file(file));
+
+        assert_eq!(loaded.device, session.device);
+        assert_eq!(loaded.channel, 9);
+        assert_eq!(loaded.transpose, -12);
+        assert!(loaded.low);
+        assert_eq!(loaded.layout, session.layout);
+        assert_eq!(loaded.velocity, 100);
+        assert_eq!(loaded.curve, session.curve);
+        assert_eq!(loaded.channels[0].program, Some(17));
+        assert_eq!(loaded.channels[0].bank, None);
+        assert_eq!(loaded.channels[9].bank, Some(200));
+        assert_eq!(loaded.channels[9].controls, vec![(7, 100), (10, 0)]);
+    }
+
+    #[test]
+    fn comments_and_unknown_keys() {
+        let file = path("comments.toml");
+        fs::write(&file, "# a session\ndevice = \"a # b\" # the synth\nreverb = 3\n\n[channels.2]\ncc.7 = 90\ncc.7 = 80\n").unwrap();
+        let session = Session::load(&file).unwrap();
+        drop(fs::remove_file(file));
+
+        assert_eq!(session.device, Some("a # b".to_string()));
+        assert_eq!(session.channels[2].controls, vec![(7, 80)]);
+    }
+
+    #[test]
+    fn bad_values() {
+        let bad = [
+            "channel = 16",
+            "transpose = 61",
+            "velocity = 0",
+            "curve = \"loud\"",
+            "device = \"unterminated",
+            "[channels.16]",
+            "[tables]",
+            "[channels.0]\nprogram = 128",
+            "[channels.0]\ncc.128 = 0",
+            "no value",
+        ];
+        for (i, text) in bad.iter().enumerate() {
+            let file = path(&format!("bad{}.toml", i));
+            fs::write(&file, text).unwrap();
+            let result = Session::load(&file);
+            drop(fs::remove_file(file));
+
+            assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use std::io::{self, Read, Write};
+
+use portmidi::MidiMessage;
+
+/// Ticks per quarter note in written files
+const DIVISION: u32 = 480;
+/// Microseconds per quarter note, 120 bpm
+const TEMPO: u32 = 500000;
+
+/// Writes a Standard MIDI File from messages timestamped in milliseconds.
+///
+/// Format 0 puts everything into a single track, format 1 writes a tempo track
+/// followed by a track for each channel used.
+pub fn write<W: Write>(mut w: W, events: &[(u32, MidiMessage)], format: u16) -> io::Result<()> {
+    let tempo = [0xFF, 0x51, 0x03, (TEMPO >> 16) as u8, (TEMPO >> 8) as u8, TEMPO as u8];
+
+    let tracks = if format == 0 {
+        let mut track = Vec::new();
+        put_event(&mut track, 0, &tempo);
+        put_messages(&mut track, events.iter());
+        vec![track]
+    } else {
+        let mut tempo_track = Vec::new();
+        put_event(&mut tempo_track, 0, &tempo);
+        put_event(&mut tempo_track, 0, &[0xFF, 0x2F, 0x00]);
+
+        let mut tracks = vec![tempo_track];
+        for chan in 0..16 {
+            let mut msgs = events.iter().filter(|&&(_, m)| m.status & 0x0F == chan).peekable();
+            if msgs.peek().is_none() { continue }
+
+            let mut track = Vec::new();
+            put_messages(&mut track, msgs);
+            tracks.push(track);
+        }
+        tracks
+    };
+
+    w.write_all(b"MThd")?;
+    w.write_all(&u32_be(6))?;
+    w.write_all(&u16_be(format))?;
+    w.write_all(&u16_be(tracks.len() as u16))?;
+    w.write_all(&u16_be(DIVISION as u16))?;
+
+    for track in tracks {
+        w.write_all(b"MTrk")?;
+        w.write_all(&u32_be(track.len() as u32))?;
+        w.write_all(&track)?;
+    }
+
+    Ok(())
+}
+
+/// Reads a Standard MIDI File of any format, returning the channel messages of all tracks
+/// merged and timestamped in milliseconds.
+pub fn read<R: Read>(mut r: R) -> io::Result<Vec<(u32, MidiMessage)>> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    let mut data = &data[..];
+
+    let (id, header) = chunk(&mut data)?;
+    if id != b"MThd" || header.len() < 6 {
+        return Err(invalid("not a midi file"))
+    }
+    let division = ((header[4] as u16) << 8) | header[5] as u16;
+
+    // Events with tick times, tempo changes are kept as (tick, tempo) pairs
+    let mut events = Vec::new();
+    let mut tempos = Vec::new();
+    while !data.is_empty() {
+        let (id, track) = chunk(&mut data)?;
+        if id == b"MTrk" {
+            read_track(track, &mut events, &mut tempos)?;
+        }
+    }
+
+    // The sort is stable, so simultaneous events of a track keep their order
+    events.sort_by_key(|&(tick, _)| tick);
+    tempos.sort_by_key(|&(tick, _)| tick);
+
+    let to_ms = |tick: u64| -> u32 {
+        if division & 0x8000 != 0 {
+            // SMPTE: frames per second and ticks per frame
+            let fps = 256 - (division >> 8) as u64;
+            let tpf = (division & 0xFF) as u64;
+            return (tick * 1000 / (fps * tpf).max(1)) as u32
+        }
+
+        let tpq = division.max(1) as u64;
+        let (mut us, mut last, mut tempo) = (0u64, 0u64, TEMPO as u64);
+        for &(t, new) in tempos.iter().take_while(|&&(t, _)| t <= tick) {
+            us += (t - last) * tempo / tpq;
+            last = t;
+            tempo = new as u64;
+        }
+        us += (tick - last) * tempo / tpq;
+
+        (us / 1000) as u32
+    };
+
+    Ok(events.into_iter().map(|(tick, msg)| (to_ms(tick), msg)).collect())
+}
+
+fn read_track(mut data: &[u8], events: &mut Vec<(u64, MidiMessage)>, tempos: &mut Vec<(u64, u32)>)
+    -> io::Result<()>
+{
+    let mut tick = 0u64;
+    let mut running = 0u8;
+
+    while !data.is_empty() {
+        tick += vlq(&mut data)? as u64;
+
+        let (status, data1) = match byte(&mut data)? {
+            0xFF => {
+                let kind = byte(&mut data)?;
+                let len = vlq(&mut data)? as usize;
+                let body = take(&mut data, len)?;
+                match kind {
+                    0x2F => break,
+                    0x51 if len == 3 => tempos.push(
+                        (tick, (body[0] as u32) << 16 | (body[1] as u32) << 8 | body[2] as u32)
+                    ),
+                    _ => (),
+                }
+                continue
+            },
+            0xF0 | 0xF7 => {
+                let len = vlq(&mut data)? as usize;
+                take(&mut data, len)?;
+                continue
+            },
+            // Running status, the byte is the first data byte
+            b if b < 0x80 => {
+                if running == 0 { return Err(invalid("running status without a status")) }
+                (running, b)
+            },
+            s => {
+                running = s;
+                (s, byte(&mut data)?)
+            },
+        };
+
+        let msg = message(status, data1, &mut data)?;
+        events.push((tick, msg));
+    }
+
+    Ok(())
+}
+
+fn message(status: u8, data1: u8, data: &mut &[u8]) -> io::Result<MidiMessage> {
+    let data2 = match status & 0xF0 {
+        0xC0 | 0xD0 => 0,
+        _ => byte(data)?,
+    };
+
+    Ok(MidiMessage {
+        status: status,
+        data1: data1,
+        data2: data2,
+    })
+}
+
+fn chunk<'a>(data: &mut &'a [u8]) -> io::Result<(&'a [u8], &'a [u8])> {
+    let id = take(data, 4)?;
+    let len = take(data, 4)?;
+    let len = (len[0] as usize) << 24 | (len[1] as usize) << 16 | (len[2] as usize) << 8 | len[3] as usize;
+    let body = take(data, len)?;
+
+    Ok((id, body))
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(invalid("unexpected end of file"))
+    }
+
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+fn byte(data: &mut &[u8]) -> io::Result<u8> {
+    take(data, 1).map(|b| b[0])
+}
+
+fn vlq(data: &mut &[u8]) -> io::Result<u32> {
+    let mut n = 0u32;
+    for _ in 0..4 {
+        let b = byte(data)?;
+        n = (n << 7) | (b & 0x7F) as u32;
+        if b & 0x80 == 0 { return Ok(n) }
+    }
+
+    Err(invalid("variable length number is too long"))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+fn put_messages<'a, I>(track: &mut Vec<u8>, events: I)
+    where I: Iterator<Item=&'a (u32, MidiMessage)>
+{
+    let mut last = 0;
+    for &(ms, msg) in events {
+        let tick = (ms as u64 * DIVISION as u64 * 1000 / TEMPO as u64) as u32;
+        let bytes = [msg.status, msg.data1, msg.data2];
+        let len = match msg.status & 0xF0 {
+            0xC0 | 0xD0 => 2,
+            _ => 3,
+        };
+
+        put_event(track, tick - last, &bytes[..len]);
+        last = tick;
+    }
+
+    put_event(track, 0, &[0xFF, 0x2F, 0x00]);
+}
+
+fn put_event(track: &mut Vec<u8>, delta: u32, bytes: &[u8]) {
+    put_vlq(track, delta);
+    track.extend_from_slice(bytes);
+}
+
+fn put_vlq(track: &mut Vec<u8>, mut n: u32) {
+    let mut buf = [0u8; 5];
+    let mut i = buf.len() - 1;
+
+    buf[i] = (n & 0x7F) as u8;
+    n >>= 7;
+    while n != 0 {
+        i -= 1;
+        buf[i] = 0x80 | (n & 0x7F) as u8;
+        n >>= 7;
+    }
+
+    track.extend_from_slice(&buf[i..]);
+}
+
+fn u32_be(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn u16_be(n: u16) -> [u8; 2] {
+    [(n >> 8) as u8, n as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(status: u8, data1: u8, data2: u8) -> MidiMessage {
+        MidiMessage {
+            status: status,
+            data1: data1,
+            data2: data2,
+        }
+    }
+
+    // Multiples of 125 ms are whole ticks at 120 bpm
+    fn events() -> Vec<(u32, MidiMessage)> {
+        vec![
+            (0, msg(0xC0, 17, 0)),
+            (0, msg(0x90, 60, 100)),
+            (125, msg(0x93, 64, 90)),
+            (500, msg(0x80, 60, 64)),
+            (1000, msg(0x83, 64, 64)),
+            (250000, msg(0xB0, 7, 100)),
+        ]
+    }
+
+    #[test]
+    fn format_0_round_trip() {
+        let mut file = vec![];
+        write(&mut file, &events(), 0).unwrap();
+
+        assert_eq!(&file[8..12], &[0, 0, 0, 1]);
+        assert_eq!(read(&file[..]).unwrap(), events());
+    }
+
+    #[test]
+    fn format_1_round_trip() {
+        let mut file = vec![];
+        write(&mut file, &events(), 1).unwrap();
+
+        // The tempo track and a track for each channel
+        assert_eq!(&file[8..12], &[0, 1, 0, 3]);
+        assert_eq!(read(&file[..]).unwrap(), events());
+    }
+
+    #[test]
+    fn running_status_and_tempo_changes() {
+        let track = [
+            0x00, 0x90, 60, 100,
+            // Running status
+            0x83, 0x60, 62, 100,
+            // 60 bpm from here on
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40,
+            0x83, 0x60, 0x80, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let mut file = b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0MTrk".to_vec();
+        file.extend_from_slice(&[0, 0, 0, track.len() as u8]);
+        file.extend_from_slice(&track);
+
+        assert_eq!(read(&file[..]).unwrap(), vec![
+            (0, msg(0x90, 60, 100)),
+            (500, msg(0x90, 62, 100)),
+            (1500, msg(0x80, 60, 0)),
+        ]);
+    }
+
+    #[test]
+    fn truncated_file() {
+        let mut file = vec![];
+        write(&mut file, &events(), 0).unwrap();
+        file.truncate(file.len() - 2);
+
+        assert_eq!(read(&file[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(read(&b"RIFF"[..]).is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use std::f32::consts::PI;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write, Seek, SeekFrom};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use portmidi::MidiMessage;
+
+use error::{Error, Result};
+use output::{Output, INTERNAL};
+
+const RATE: u32 = 44100;
+const BLOCK: usize = 256;
+const VOICES: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+enum Wave {
+    Sine,
+    Triangle,
+    Square,
+    Saw,
+}
+
+/// An oscillator with an ADSR envelope, times are in seconds
+struct Preset {
+    wave: Wave,
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+}
+
+/// Chosen by the program number modulo their count
+const PRESETS: &[Preset] = &[
+    // Organ
+    Preset { wave: Wave::Sine, attack: 0.005, decay: 0.0, sustain: 1.0, release: 0.05 },
+    // Piano
+    Preset { wave: Wave::Triangle, attack: 0.002, decay: 1.5, sustain: 0.0, release: 0.2 },
+    // Lead
+    Preset { wave: Wave::Square, attack: 0.01, decay: 0.2, sustain: 0.6, release: 0.1 },
+    // Strings
+    Preset { wave: Wave::Saw, attack: 0.3, decay: 0.5, sustain: 0.8, release: 0.6 },
+    // Pluck
+    Preset { wave: Wave::Saw, attack: 0.001, decay: 0.4, sustain: 0.0, release: 0.05 },
+];
+
+/// A polyphonic synth playing in its own thread in real time, either into a WAV file
+/// or as raw 16-bit mono samples on the standard output (`-`)
+pub struct Synth {
+    tx: Sender<(Instant, MidiMessage)>,
+    errors: Receiver<Error>,
+    name: String,
+}
+
+impl Synth {
+    pub fn new(path: &str) -> io::Result<Self> {
+        let sink = if path == "-" { Sink::Raw(io::stdout()) } else {
+            // A take isn't overwritten
+            let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+            file.write_all(&wav_header(0))?;
+            Sink::Wav(file, 0)
+        };
+
+        let (tx, rx) = channel();
+        let (err_tx, errors) = channel();
+        let out = if path == "-" { "standard output".to_string() } else { path.to_string() };
+        thread::spawn(move || run(sink, rx, out, err_tx));
+
+        Ok(Synth {
+            tx: tx,
+            errors: errors,
+            name: if path == "-" { "internal".to_string() } else { format!("internal → {}", path) },
+        })
+    }
+}
+
+impl Output for Synth {
+    fn write_at(&mut self, time: Instant, msg: MidiMessage) -> Result<()> {
+        if let Ok(e) = self.errors.try_recv() { return Err(e) }
+        self.tx.send((time, msg)).map_err(|_| Error::Disconnected(self.name.clone()))
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn id(&self) -> i32 {
+        INTERNAL
+    }
+}
+
+enum Sink {
+    Raw(io::Stdout),
+    /// The file and the size of the samples written
+    Wav(File, u32),
+}
+
+impl Sink {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(2 * samples.len());
+        for &s in samples {
+            let v = (s.clamp(-1.0, 1.0) * 32767.0) as i16;
+            bytes.push(v as u8);
+            bytes.push((v >> 8) as u8);
+        }
+
+        match *self {
+            Sink::Raw(ref mut out) => {
+                out.write_all(&bytes)?;
+                out.flush()
+            },
+            Sink::Wav(ref mut file, ref mut size) => {
+                file.write_all(&bytes)?;
+                *size += bytes.len() as u32;
+
+                // The header is kept up to date, so the file is complete whenever vmjk exits
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&wav_header(*size))?;
+                file.seek(SeekFrom::End(0)).map(|_| ())
+            },
+        }
+    }
+}
+
+fn wav_header(size: u32) -> Vec<u8> {
+    let mut header = vec![];
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32_le(36 + size));
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&u32_le(16));
+    // PCM, one channel
+    header.extend_from_slice(&[1, 0, 1, 0]);
+    header.extend_from_slice(&u32_le(RATE));
+    header.extend_from_slice(&u32_le(2 * RATE));
+    // Block align and bits per sample
+    header.extend_from_slice(&[2, 0, 16, 0]);
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32_le(size));
+    header
+}
+
+fn u32_le(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+/// Renders a block whenever its time has passed, playing the messages at their samples
+fn run(mut sink: Sink, rx: Receiver<(Instant, MidiMessage)>, out: String, errors: Sender<Error>) {
+    let mut engine = Engine::new();
+    let mut queue: Vec<(Instant, MidiMessage)> = vec![];
+    let start = Instant::now();
+    let mut done: u64 = 0;
+
+    // The sample of the block at which a message is played
+    let offset = |time: Instant, done: u64| -> usize {
+        if time <= start { return 0 }
+        let d = time.duration_since(start);
+        let frame = d.as_secs() * RATE as u64 + d.subsec_nanos() as u64 * RATE as u64 / 1000000000;
+        frame.saturating_sub(done) as usize
+    };
+
+    loop {
+        let end = start + frames(done + BLOCK as u64);
+        let now = Instant::now();
+        if end > now { thread::sleep(end - now) }
+
+        loop {
+            match rx.try_recv() {
+                Ok((time, msg)) => {
+                    let i = queue.iter().position(|&(t, _)| t > time).unwrap_or(queue.len());
+                    queue.insert(i, (time, msg));
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        let mut samples = [0.0; BLOCK];
+        let mut i = 0;
+        while i < BLOCK {
+            while queue.first().is_some_and(|&(t, _)| offset(t, done) <= i) {
+                engine.handle(queue.remove(0).1);
+            }
+
+            let next = queue.first().map(|&(t, _)| offset(t, done).min(BLOCK)).unwrap_or(BLOCK);
+            engine.render(&mut samples[i..next]);
+            i = next;
+        }
+
+        if let Err(e) = sink.write(&samples) {
+            return drop(errors.send(Error::File(out, e)))
+        }
+        done += BLOCK as u64;
+    }
+}
+
+fn frames(n: u64) -> Duration {
+    Duration::new(n / RATE as u64, ((n % RATE as u64) * 1000000000 / RATE as u64) as u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Release,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    chan: u8,
+    note: u8,
+    velocity: f32,
+    phase: f32,
+    level: f32,
+    stage: Stage,
+    /// The key is still down
+    held: bool,
+    age: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    program: u8,
+    volume: f32,
+    modulation: f32,
+    sustain: bool,
+    bend: i32,
+    /// Pitch bend range in semitones
+    range: f32,
+    rpn: (u8, u8),
+}
+
+struct Engine {
+    voices: [Voice; VOICES],
+    chans: [Channel; 16],
+    clock: u64,
+    lfo: f32,
+}
+
+impl Engine {
+    fn new() -> Self {
+        let voice = Voice {
+            chan: 0,
+            note: 0,
+            velocity: 0.0,
+            phase: 0.0,
+            level: 0.0,
+            stage: Stage::Off,
+            held: false,
+            age: 0,
+        };
+        let chan = Channel {
+            program: 0,
+            volume: 100.0 / 127.0,
+            modulation: 0.0,
+            sustain: false,
+            bend: 8192,
+            range: 2.0,
+            rpn: (127, 127),
+        };
+
+        Engine {
+            voices: [voice; VOICES],
+            chans: [chan; 16],
+            clock: 0,
+            lfo: 0.0,
+        }
+    }
+
+    fn handle(&mut self, msg: MidiMessage) {
+        let c = (msg.status & 0x0F) as usize;
+        match (msg.status & 0xF0, msg.data1, msg.data2) {
+            (0x90, note, vel) if vel > 0 => self.note_on(c as u8, note, vel),
+            (0x80, note, _) | (0x90, note, _) => {
+                let sustain = self.chans[c].sustain;
+                for v in self.voices.iter_mut().filter(|v| v.held && v.chan == c as u8 && v.note == note) {
+                    v.held = false;
+                    if !sustain { v.stage = Stage::Release }
+                }
+            },
+            (0xB0, 1, value) => self.chans[c].modulation = value as f32 / 127.0,
+            (0xB0, 6, value) => match self.chans[c].rpn {
+                (0, 0) => self.chans[c].range = value as f32,
+                // MPE zone configuration, member channels bend by 48 semitones
+                (0, 6) if c == 0 => {
+                    for chan in self.chans[1..].iter_mut().take(value as usize) {
+                        chan.range = 48.0
+                    }
+                },
+                _ => (),
+            },
+            (0xB0, 7, value) => self.chans[c].volume = value as f32 / 127.0,
+            (0xB0, 38, value) if self.chans[c].rpn == (0, 0) => {
+                self.chans[c].range = self.chans[c].range.floor() + value as f32 / 100.0
+            },
+            (0xB0, 64, value) => {
+                self.chans[c].sustain = value >= 64;
+                if value < 64 {
+                    for v in self.voices.iter_mut().filter(|v| v.chan == c as u8 && !v.held) {
+                        if v.stage != Stage::Off { v.stage = Stage::Release }
+                    }
+                }
+            },
+            (0xB0, 100, value) => self.chans[c].rpn.1 = value,
+            (0xB0, 101, value) => self.chans[c].rpn.0 = value,
+            // All sound off
+            (0xB0, 120, _) => {
+                for v in self.voices.iter_mut().filter(|v| v.chan == c as u8) { v.stage = Stage::Off }
+            },
+            // All notes off
+            (0xB0, 123, _) => {
+                for v in self.voices.iter_mut().filter(|v| v.chan == c as u8 && v.stage != Stage::Off) {
+                    v.held = false;
+                    v.stage = Stage::Release;
+                }
+            },
+            (0xC0, program, _) => self.chans[c].program = program,
+            (0xE0, lsb, msb) => self.chans[c].bend = lsb as i32 | (msb as i32) << 7,
+            _ => (),
+        }
+    }
+
+    fn note_on(&mut self, chan: u8, note: u8, velocity: u8) {
+        self.clock += 1;
+
+        // A free voice or the oldest one
+        let i = self.voices.iter().position(|v| v.stage == Stage::Off).unwrap_or_else(|| {
+            (0..VOICES).min_by_key(|&i| self.voices[i].age).unwrap_or(0)
+        });
+
+        self.voices[i] = Voice {
+            chan: chan,
+            note: note,
+            velocity: velocity as f32 / 127.0,
+            phase: 0.0,
+            level: 0.0,
+            stage: Stage::Attack,
+            held: true,
+            age: self.clock,
+        };
+    }
+
+    fn render(&mut self, out: &mut [f32]) {
+        let dt = 1.0 / RATE as f32;
+        let lfo_start = self.lfo;
+
+        for v in self.voices.iter_mut().filter(|v| v.stage != Stage::Off) {
+            let chan = self.chans[v.chan as usize];
+            let preset = &PRESETS[chan.program as usize % PRESETS.len()];
+            let bend = (chan.bend - 8192) as f32 / 8192.0 * chan.range;
+            let gain = 0.2 * v.velocity * chan.volume;
+            let mut lfo = lfo_start;
+
+            for s in out.iter_mut() {
+                // Vibrato up to a quarter tone from the modulation wheel
+                let vibrato = 0.5 * chan.modulation * (2.0 * PI * lfo).sin();
+                lfo = (lfo + 5.5 * dt).fract();
+
+                let pitch = v.note as f32 - 69.0 + bend + vibrato;
+                let freq = 440.0 * (pitch / 12.0).exp2();
+                v.phase = (v.phase + freq * dt).fract();
+
+                let wave = match preset.wave {
+                    Wave::Sine => (2.0 * PI * v.phase).sin(),
+                    Wave::Triangle => 1.0 - 4.0 * (v.phase - 0.5).abs(),
+                    Wave::Square => if v.phase < 0.5 { 0.5 } else { -0.5 },
+                    Wave::Saw => 0.6 * (2.0 * v.phase - 1.0),
+                };
+
+                match v.stage {
+                    Stage::Attack => {
+                        v.level += dt / preset.attack;
+                        if v.level >= 1.0 {
+                            v.level = 1.0;
+                            v.stage = Stage::Decay;
+                        }
+                    },
+                    Stage::Decay if v.level > preset.sustain => {
+                        v.level = (v.level - dt / preset.decay.max(dt)).max(preset.sustain);
+                    },
+                    Stage::Decay => if preset.sustain == 0.0 { v.stage = Stage::Off },
+                    Stage::Release => {
+                        v.level -= dt / preset.release;
+                        if v.level <= 0.0 {
+                            v.level = 0.0;
+                            v.stage = Stage::Off;
+                        }
+                    },
+                    Stage::Off => (),
+                }
+
+                *s += gain * v.level * wave;
+            }
+        }
+
+        self.lfo = (lfo_start + 5.5 * dt * out.len() as f32).fract();
+    }
+}
@@ -0,0 +1,323 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use key::Key;
+use layout::into_key;
+use ui::{Shade, Wheel};
+use {MusicBox, Screen};
+
+/// How long a key counts as held after it's pressed. Terminals don't tell when a key
+/// is released, only repeat it after a delay while it's held.
+const FIRST_HOLD: u64 = 600;
+
+/// How long a repeated key is held further
+const REPEAT_HOLD: u64 = 120;
+
+/// Width of a hexagon in characters
+const CELL: usize = 4;
+
+/// Lines of the screen with the first row of hexagons and the strips
+const HEX_LINE: u16 = 2;
+const BAR_LINE: u16 = 7;
+
+/// Columns where the strips start and their width
+const BEND_BAR: u16 = 5;
+const MOD_BAR: u16 = 34;
+const BAR: u16 = 16;
+
+/// What's read from the terminal
+enum Input {
+    /// A key with Ctrl and the character typed
+    Key(Key, bool, Option<char>),
+    /// The left button pressed or dragged at a column and a line, counting from 0
+    Press(u16, u16),
+    Drag(u16, u16),
+    Release,
+    Nothing,
+}
+
+/// The keyboard drawn in the terminal with ANSI escapes
+pub struct Terminal {
+    tty: File,
+    saved: libc::termios,
+    /// Keys that are down with the time they are released
+    down: Vec<(Key, Instant)>,
+}
+
+impl Terminal {
+    pub fn new() -> io::Result<Self> {
+        let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let fd = tty.as_raw_fd();
+
+        let saved = unsafe {
+            let mut saved = ::std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut saved) != 0 { return Err(io::Error::last_os_error()) }
+            saved
+        };
+
+        // Raw mode, reads return at once even when there's nothing to read
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        // The alternate screen without the cursor and wrapping the long lines, reporting
+        // the mouse buttons and drags
+        drop(tty.write_all(b"\x1b[?1049h\x1b[?25l\x1b[?7l\x1b[2J\x1b[?1002h\x1b[?1006h"));
+
+        Ok(Terminal {
+            tty: tty,
+            saved: saved,
+            down: vec![],
+        })
+    }
+
+    fn key(&mut self, the_box: &mut MusicBox, key: Key, ctrl: bool, ch: Option<char>) {
+        // Unknown keys are only typed, the layout has a note on them for the window
+        let now = Instant::now();
+        match self.down.iter().position(|&(k, _)| k == key) {
+            _ if key == Key::Unknown => (),
+            Some(i) => self.down[i].1 = now + Duration::from_millis(REPEAT_HOLD),
+            None => {
+                the_box.press(key, ctrl);
+                self.down.push((key, now + Duration::from_millis(FIRST_HOLD)))
+            },
+        }
+
+        if let Some(ch) = ch {
+            the_box.text(ch)
+        }
+    }
+
+    /// The point of the window under a character: the center of a hexagon or a point
+    /// on a strip, the strip being dragged takes the whole line
+    fn point(&self, the_box: &MusicBox, col: u16, line: u16) -> (i32, i32) {
+        let bar = |start: u16| (col as f32 - start as f32 + 0.5) / BAR as f32;
+        let wheel = match the_box.dragging {
+            Some(Wheel::Bend) => Some((Wheel::Bend, bar(BEND_BAR))),
+            Some(Wheel::Modulation) => Some((Wheel::Modulation, bar(MOD_BAR))),
+            None if line == BAR_LINE && (BEND_BAR..BEND_BAR + BAR).contains(&col) => Some((Wheel::Bend, bar(BEND_BAR))),
+            None if line == BAR_LINE && (MOD_BAR..MOD_BAR + BAR).contains(&col) => Some((Wheel::Modulation, bar(MOD_BAR))),
+            None => None,
+        };
+        if let Some((wheel, pos)) = wheel {
+            let (x, y) = the_box.wheels.point(wheel, pos);
+            return (x as i32, y as i32)
+        }
+
+        let col = col as usize;
+        the_box.hexes.centers().into_iter()
+            .find(|&(_, _, row, offset, _)| {
+                let start = offset as usize * CELL / 2;
+                // The space after a hexagon belongs to it, so dragging doesn't fall in gaps
+                line == HEX_LINE + row as u16 && col >= start && col < start + CELL
+            })
+            .map(|(x, y, _, _, _)| (x as i32, y as i32))
+            .unwrap_or((-1, -1))
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        drop(self.tty.write_all(b"\x1b[?1006l\x1b[?1002l\x1b[0m\x1b[?7h\x1b[?25h\x1b[?1049l"));
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.saved) };
+    }
+}
+
+impl Screen for Terminal {
+    fn poll(&mut self, the_box: &mut MusicBox) -> bool {
+        let mut buf = [0; 64];
+        let n = match self.tty.read(&mut buf) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(_) => return false,
+        };
+
+        let mut bytes = &buf[..n];
+        while !bytes.is_empty() {
+            let (len, input) = parse(bytes);
+            bytes = &bytes[len..];
+
+            match input {
+                // Ctrl-C quits, the window would be closed instead
+                Input::Key(Key::C, true, _) => return false,
+                Input::Key(key, ctrl, ch) => self.key(the_box, key, ctrl, ch),
+                Input::Press(col, line) => {
+                    let (x, y) = self.point(the_box, col, line);
+                    the_box.mouse_press(x, y)
+                },
+                Input::Drag(col, line) => {
+                    let (x, y) = self.point(the_box, col, line);
+                    the_box.mouse_move(x, y)
+                },
+                Input::Release => the_box.mouse_release(),
+                Input::Nothing => (),
+            }
+        }
+
+        let now = Instant::now();
+        let released: Vec<_> = self.down.iter().filter(|&&(_, t)| t <= now).map(|&(k, _)| k).collect();
+        self.down.retain(|&(_, t)| t > now);
+        for key in released {
+            the_box.release(key)
+        }
+
+        true
+    }
+
+    fn draw(&mut self, the_box: &MusicBox) {
+        let mut out = String::from("\x1b[H");
+        let line_end = "\x1b[0m\x1b[K\r\n";
+
+        out.push_str(&the_box.status());
+        if the_box.recorder.is_recording() {
+            out.push_str(" \x1b[38;5;203m●");
+        }
+        out.push_str(line_end);
+        out.push_str(line_end);
+
+        // The upper rows of the pairs are shifted by half a hexagon
+        let hexes = &the_box.hexes;
+        let mut rows = vec![String::new(); 4];
+        for (_, _, row, offset, white) in hexes.centers() {
+            let line = &mut rows[row as usize];
+            if line.is_empty() && offset % 2 == 1 {
+                line.push_str(&" ".repeat(CELL / 2))
+            }
+
+            let (bg, fg) = match (white, hexes.shade(offset)) {
+                (_, None) => (236, 236),
+                (false, Some(Shade::Pressed)) => (238, 252),
+                (true, Some(Shade::Pressed)) => (103, 236),
+                (false, Some(Shade::Remote)) => (23, 252),
+                (true, Some(Shade::Remote)) => (116, 236),
+                (false, Some(Shade::Guide)) => (54, 252),
+                (true, Some(Shade::Guide)) => (147, 236),
+                (false, Some(Shade::Sustained)) => (95, 252),
+                (true, Some(Shade::Sustained)) => (181, 236),
+                (false, Some(Shade::Normal)) => (16, 252),
+                (true, Some(Shade::Normal)) => (231, 236),
+            };
+            let label: String = hexes.label(row, offset).into_iter().next().unwrap_or_default()
+                .chars().take(CELL - 1).collect();
+
+            line.push_str(&format!("\x1b[48;5;{}m\x1b[38;5;{}m{:^w$}\x1b[0m ", bg, fg, label, w = CELL - 1));
+        }
+        for line in rows {
+            out.push_str(&line);
+            out.push_str(line_end);
+        }
+        out.push_str(line_end);
+
+        let wheels = &the_box.wheels;
+        out.push_str(&format!("bend {} {:+5}   mod {} {:3}", bar(wheels.bend(), 16383), wheels.bend() - 8192,
+            bar(wheels.modulation() as i32, 127), wheels.modulation()));
+        out.push_str(line_end);
+
+        if the_box.cmd_mode {
+            out.push_str(line_end);
+            for line in the_box.cmd.text().lines() {
+                out.push_str(line);
+                out.push_str(line_end);
+            }
+        }
+        out.push_str("\x1b[J");
+
+        drop(self.tty.write_all(out.as_bytes()).and_then(|_| self.tty.flush()))
+    }
+}
+
+fn bar(value: i32, max: i32) -> String {
+    let width = 16;
+    let full = (value * width + max / 2) / max;
+    (0..width).map(|i| if i < full { '█' } else { '░' }).collect()
+}
+
+/// Reads a key or a mouse event from the start of the input, with the bytes it takes
+fn parse(bytes: &[u8]) -> (usize, Input) {
+    let key = |key, ctrl, ch| (1, Input::Key(key, ctrl, ch));
+    match bytes[0] {
+        0x1B if bytes.len() == 1 => key(Key::Escape, false, None),
+        0x1B if bytes[1] == b'[' || bytes[1] == b'O' => escape(bytes),
+        0x1B => key(Key::Escape, false, None),
+        0x0D | 0x0A => key(Key::Return, false, Some('\r')),
+        0x7F | 0x08 => key(Key::BackSpace, false, Some('\u{8}')),
+        0x09 => key(Key::Tab, false, None),
+        b @ 0x01..=0x1A => key(into_key(b + 0x60), true, None),
+        // The numpad keys can't be told apart, `-` and `/` play notes
+        b'+' => key(Key::Add, false, Some('+')),
+        b'_' => key(Key::Subtract, false, Some('_')),
+        b'*' => key(Key::Multiply, false, Some('*')),
+        b'?' => key(Key::Divide, false, Some('?')),
+        b @ 0x20..=0x7E => key(into_key((b as char).to_ascii_lowercase() as u8), false, Some(b as char)),
+        b if b < 0x80 => (1, Input::Nothing),
+        b => {
+            // The rest of a multibyte character
+            let len = (if b >= 0xF0 { 4 } else if b >= 0xE0 { 3 } else { 2 }).min(bytes.len());
+            let ch = ::std::str::from_utf8(&bytes[..len]).ok().and_then(|s| s.chars().next());
+            (len, Input::Key(Key::Unknown, false, ch))
+        },
+    }
+}
+
+/// Reads the escape sequences of the cursor and the function keys, like `ESC [ 1 ; 5 H`
+/// for Ctrl+Home, and the mouse events like `ESC [ < 0 ; 12 ; 4 M`
+fn escape(bytes: &[u8]) -> (usize, Input) {
+    let end = match bytes.iter().skip(2).position(|b| (0x40..=0x7E).contains(b)) {
+        Some(i) => i + 2,
+        None => return (bytes.len(), Input::Nothing),
+    };
+    let mouse = bytes.get(2) == Some(&b'<');
+    let start = if mouse { 3 } else { 2 };
+    let params: Vec<u16> = ::std::str::from_utf8(&bytes[start..end]).unwrap_or("")
+        .split(';').map(|p| p.parse().unwrap_or(1)).collect();
+
+    if mouse {
+        let (col, line) = match (params.get(1), params.get(2)) {
+            (Some(&c), Some(&l)) => (c.saturating_sub(1), l.saturating_sub(1)),
+            _ => return (end + 1, Input::Nothing),
+        };
+        // Only the left button, 32 is added while it moves
+        let input = match (params[0], bytes[end]) {
+            (0, b'M') => Input::Press(col, line),
+            (32, b'M') => Input::Drag(col, line),
+            (0, b'm') => Input::Release,
+            _ => Input::Nothing,
+        };
+        return (end + 1, input)
+    }
+
+    let ctrl = params.get(1).map(|&m| m.saturating_sub(1) & 4 != 0).unwrap_or(false);
+
+    let key = match (bytes[end], params[0]) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) => Key::Home,
+        (b'F', _) => Key::End,
+        (b'P', _) => Key::F1,
+        (b'Q', _) => Key::F2,
+        (b'R', _) => Key::F3,
+        (b'S', _) => Key::F4,
+        (b'~', 1) | (b'~', 7) => Key::Home,
+        (b'~', 4) | (b'~', 8) => Key::End,
+        (b'~', 2) => Key::Insert,
+        (b'~', 3) => Key::Delete,
+        (b'~', 5) => Key::PageUp,
+        (b'~', 6) => Key::PageDown,
+        (b'~', 11) => Key::F1,
+        (b'~', 12) => Key::F2,
+        _ => return (end + 1, Input::Nothing),
+    };
+
+    (end + 1, Input::Key(key, ctrl, None))
+}
@@ -0,0 +1,277 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The largest scale degree accepted in a keyboard mapping
+const MAX_DEGREE: i32 = 1 << 16;
+
+/// A scale from a Scala `.scl` file mapped to the midi keys, optionally by a `.kbm` file
+#[derive(Debug)]
+pub struct Tuning {
+    name: String,
+    /// Pitch of every midi key in (fractional) midi note numbers, `None` if unmapped
+    pitches: Vec<Option<f64>>,
+}
+
+/// A keyboard mapping, see http://www.huygens-fokker.org/scala/help.htm#mappings
+struct Mapping {
+    first: u8,
+    last: u8,
+    middle: i32,
+    reference: i32,
+    frequency: f64,
+    octave: i32,
+    map: Vec<Option<i32>>,
+}
+
+impl Tuning {
+    pub fn load<P: AsRef<Path>>(scl: P, kbm: Option<P>) -> io::Result<Self> {
+        let name = scl.as_ref().file_stem()
+            .map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let scale = parse_scl(&read(scl)?)?;
+        let mapping = match kbm {
+            Some(path) => parse_kbm(&read(path)?)?,
+            // Degree 0 on the middle C, tuned as in 12-TET
+            None => Mapping {
+                first: 0,
+                last: 127,
+                middle: 60,
+                reference: 60,
+                frequency: 440.0 * 2f64.powf(-9.0 / 12.0),
+                octave: scale.len() as i32,
+                map: vec![],
+            },
+        };
+
+        let cents = |degree: i32| -> f64 {
+            let n = scale.len() as i32;
+            let period = *scale.last().unwrap();
+            let (octave, step) = (div_floor(degree, n), degree - n * div_floor(degree, n));
+            let step = if step == 0 { 0.0 } else { scale[step as usize - 1] };
+
+            octave as f64 * period + step
+        };
+
+        let degree = |key: i32| -> Option<i32> {
+            let d = key.checked_sub(mapping.middle)?;
+            if mapping.map.is_empty() { return Some(d) }
+
+            let m = mapping.map.len() as i32;
+            let octave = div_floor(d, m);
+            let deg = mapping.map[(d - octave * m) as usize]?;
+            octave.checked_mul(mapping.octave).and_then(|o| o.checked_add(deg))
+        };
+
+        // The reference key sounds at the reference frequency even if it's unmapped
+        let reference = cents(degree(mapping.reference).unwrap_or(0));
+        let reference_pitch = 69.0 + 12.0 * (mapping.frequency / 440.0).log2();
+
+        let pitches = (0..128).map(|key| {
+            if key < mapping.first as i32 || key > mapping.last as i32 { return None }
+            degree(key).map(|d| reference_pitch + (cents(d) - reference) / 100.0)
+        }).collect();
+
+        Ok(Tuning {
+            name: name,
+            pitches: pitches,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The 12-TET note nearest to the pitch of a key and the deviation from it in cents
+    pub fn note(&self, key: u8) -> Option<(u8, i32)> {
+        self.pitches[key as usize].and_then(|pitch| {
+            let note = pitch.round();
+            if !(0.0..=127.0).contains(&note) { return None }
+
+            Some((note as u8, ((pitch - note) * 100.0).round() as i32))
+        })
+    }
+
+    /// Deviation of every key from 12-TET in cents
+    pub fn deviations(&self) -> Vec<Option<i32>> {
+        self.pitches.iter().enumerate()
+            .map(|(key, p)| p.map(|p| ((p - key as f64) * 100.0).round() as i32))
+            .collect()
+    }
+}
+
+fn read<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// Lines without the `!` comments
+fn lines(text: &str) -> Vec<&str> {
+    text.lines().filter(|l| !l.starts_with('!')).collect()
+}
+
+/// Scale degrees in cents, the last one is the period
+fn parse_scl(text: &str) -> io::Result<Vec<f64>> {
+    let lines = lines(text);
+
+    // The first line is the description, which can be empty
+    let count: usize = lines.get(1).and_then(|l| l.trim().parse().ok())
+        .ok_or_else(|| invalid("no number of notes in the scale"))?;
+    if count == 0 || lines.len() - 2 < count {
+        return Err(invalid("not enough notes in the scale"))
+    }
+
+    lines[2..count + 2].iter().map(|l| {
+        let pitch = l.split_whitespace().next().unwrap_or("");
+        if pitch.contains('.') {
+            pitch.parse::<f64>().map_err(|_| invalid("bad cents value"))
+        } else {
+            let mut ratio = pitch.splitn(2, '/');
+            let num = ratio.next().and_then(|n| n.parse::<f64>().ok());
+            let den = ratio.next().map(|d| d.parse::<f64>().ok()).unwrap_or(Some(1.0));
+            match (num, den) {
+                (Some(n), Some(d)) if n > 0.0 && d > 0.0 => Ok(1200.0 * (n / d).log2()),
+                _ => Err(invalid("bad ratio")),
+            }
+        }
+    }).collect()
+}
+
+fn parse_kbm(text: &str) -> io::Result<Mapping> {
+    let lines = lines(text);
+    if lines.len() < 7 {
+        return Err(invalid("keyboard mapping is too short"))
+    }
+
+    let number = |i: usize| -> io::Result<i32> {
+        lines[i].split_whitespace().next().and_then(|n| n.parse().ok())
+            .ok_or_else(|| invalid("bad number in the keyboard mapping"))
+    };
+    // Keys and degrees are kept small enough to be mapped without overflows
+    let ranged = |i: usize, max: i32| -> io::Result<i32> {
+        number(i).and_then(|n| if n >= 0 && n <= max { Ok(n) } else {
+            Err(invalid("number out of range in the keyboard mapping"))
+        })
+    };
+    let size = ranged(0, 127)? as usize;
+    let frequency = lines[5].split_whitespace().next().and_then(|n| n.parse().ok())
+        .filter(|&f: &f64| f > 0.0)
+        .ok_or_else(|| invalid("bad reference frequency"))?;
+
+    let map = lines.iter().skip(7).take(size).map(|l| {
+        match l.split_whitespace().next() {
+            Some("x") | None => Ok(None),
+            Some(d) => match d.parse::<i32>() {
+                Ok(d) if d.abs() <= MAX_DEGREE => Ok(Some(d)),
+                _ => Err(invalid("bad degree in the keyboard mapping")),
+            },
+        }
+    }).collect::<io::Result<Vec<_>>>()?;
+
+    Ok(Mapping {
+        first: ranged(1, 127)? as u8,
+        last: ranged(2, 127)? as u8,
+        middle: ranged(3, 127)?,
+        reference: ranged(4, 127)?,
+        frequency: frequency,
+        octave: ranged(6, MAX_DEGREE)?,
+        map: map,
+    })
+}
+
+fn div_floor(a: i32, b: i32) -> i32 {
+    let d = a / b;
+    if (a % b != 0) && ((a < 0) != (b < 0)) { d - 1 } else { d }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+
+    fn file(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("vmjk-{}-{}", process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn kbm(lines: &[&str]) -> io::Result<Mapping> {
+        parse_kbm(&lines.join("\n"))
+    }
+
+    #[test]
+    fn scale_without_mapping() {
+        let scl = file("quarter.scl", "! quarter.scl\n!\nTwo steps\n 2\n!\n150.0 cents\n2/1\n");
+        let tuning = Tuning::load(&scl, None).unwrap();
+        drop(fs::remove_file(scl));
+
+        assert!(tuning.name().ends_with("quarter"));
+        assert_eq!(tuning.note(60), Some((60, 0)));
+        assert_eq!(tuning.note(61), Some((62, -50)));
+        assert_eq!(tuning.note(62), Some((72, 0)));
+        assert_eq!(tuning.note(58), Some((48, 0)));
+        // Beyond the midi range
+        assert_eq!(tuning.note(80), None);
+        assert_eq!(tuning.deviations()[61], Some(50));
+    }
+
+    #[test]
+    fn scale_with_mapping() {
+        let steps: Vec<String> = (1..13).map(|s| format!("{}.0", s * 100)).collect();
+        let scl = file("tet.scl", &format!("12-TET\n12\n{}\n", steps.join("\n")));
+        let kbm = file("three.kbm", "! three keys\n3\n48\n72\n60\n69\n440.0\n12\n0\nx\n2\n");
+        let tuning = Tuning::load(&scl, Some(&kbm)).unwrap();
+        drop(fs::remove_file(scl));
+        drop(fs::remove_file(kbm));
+
+        // Every three keys go up an octave, the key 69 stays at 440 Hz
+        assert_eq!(tuning.note(69), Some((69, 0)));
+        assert_eq!(tuning.note(60), Some((33, 0)));
+        assert_eq!(tuning.note(61), None);
+        assert_eq!(tuning.note(62), Some((35, 0)));
+        assert_eq!(tuning.note(63), Some((45, 0)));
+        assert_eq!(tuning.note(47), None);
+        assert_eq!(tuning.note(73), None);
+    }
+
+    #[test]
+    fn bad_scales() {
+        assert!(parse_scl("no count\n").is_err());
+        assert!(parse_scl("too short\n3\n100.0\n").is_err());
+        assert!(parse_scl("bad ratio\n1\n3/0\n").is_err());
+        assert_eq!(parse_scl("ratio\n2\n3/2\n2\n").unwrap()[1], 1200.0);
+    }
+
+    #[test]
+    fn bad_mappings() {
+        let ok = ["12", "0", "127", "60", "69", "440.0", "12"];
+        assert!(kbm(&ok).is_ok());
+
+        let mut size = ok;
+        size[0] = "200";
+        assert_eq!(kbm(&size).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        let mut middle = ok;
+        middle[3] = "-1";
+        assert!(kbm(&middle).is_err());
+
+        let mut octave = ok;
+        octave[6] = "2147483647";
+        assert!(kbm(&octave).is_err());
+
+        let mut frequency = ok;
+        frequency[5] = "0";
+        assert!(kbm(&frequency).is_err());
+
+        assert!(kbm(&["1", "0", "127", "60", "69", "440.0", "12", "99999999"]).is_err());
+        assert!(kbm(&ok[..6]).is_err());
+    }
+}
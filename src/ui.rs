@@ -1,4 +1,7 @@
-use sfml::graphics::{Drawable, RenderTarget, RenderStates, CircleShape, Color, Shape, Transformable};
+#[cfg(feature = "gui")]
+use sfml::graphics::{Drawable, RenderTarget, RenderStates, CircleShape, RectangleShape, Color, Shape, Transformable};
+#[cfg(feature = "gui")]
+use sfml::graphics::{Text, Font};
 
 const GAP: f32 = 0.175;
 
@@ -8,7 +11,7 @@ const DIAG_Y: f32 = 1.5 + 0.707 * GAP;
 
 #[derive(Debug)]
 pub struct Hexes {
-    note: u8,
+    note: i32,
     radius: f32,
     width: f32,
     height: f32,
@@ -16,6 +19,65 @@ pub struct Hexes {
     y: f32,
     margin: f32,
     pressed: Vec<u8>,
+    sustained: Vec<u8>,
+    remote: Vec<u8>,
+    guide: Vec<u8>,
+    labels: bool,
+    spelling: Spelling,
+    key_labels: bool,
+    keys: Vec<(u8, u8, String)>,
+    cents: Option<Vec<Option<i32>>>,
+}
+
+/// How a hexagon is lit, in the order of priority
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shade {
+    Pressed,
+    Remote,
+    Guide,
+    Sustained,
+    Normal,
+}
+
+/// How the note names are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Spelling {
+    Sharps,
+    Flats,
+    /// B is H and B♭ is B
+    German,
+    Solfege,
+    Midi,
+}
+
+impl Spelling {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sharps" => Some(Spelling::Sharps),
+            "flats" => Some(Spelling::Flats),
+            "german" => Some(Spelling::German),
+            "solfege" => Some(Spelling::Solfege),
+            "midi" => Some(Spelling::Midi),
+            _ => None,
+        }
+    }
+
+    /// The name of the note without the octave
+    pub fn name(&self, note: u8) -> String {
+        const SHARPS: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+        const FLATS: [&str; 12] = ["C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B"];
+        const GERMAN: [&str; 12] = ["C", "Cis", "D", "Dis", "E", "F", "Fis", "G", "Gis", "A", "B", "H"];
+        const SOLFEGE: [&str; 12] = ["Do", "Do#", "Re", "Re#", "Mi", "Fa", "Fa#", "Sol", "Sol#", "La", "La#", "Si"];
+
+        let pc = note as usize % 12;
+        match *self {
+            Spelling::Sharps => SHARPS[pc].to_string(),
+            Spelling::Flats => FLATS[pc].to_string(),
+            Spelling::German => GERMAN[pc].to_string(),
+            Spelling::Solfege => SOLFEGE[pc].to_string(),
+            Spelling::Midi => note.to_string(),
+        }
+    }
 }
 
 impl Hexes {
@@ -30,6 +92,14 @@ impl Hexes {
             y: 0.0,
             margin: margin,
             pressed: vec![],
+            sustained: vec![],
+            remote: vec![],
+            guide: vec![],
+            labels: false,
+            spelling: Spelling::Sharps,
+            key_labels: false,
+            keys: vec![],
+            cents: None,
         }
     }
 
@@ -52,6 +122,7 @@ impl Hexes {
     }
 
     pub fn press(&mut self, note: u8) {
+        self.sustained.retain(|n| *n != note);
         self.pressed.push(note);
     }
 
@@ -59,19 +130,347 @@ impl Hexes {
         self.pressed.retain(|n| *n != note);
     }
 
+    /// Marks a note that is released but still held by the sustain pedal
+    pub fn sustain(&mut self, note: u8) {
+        self.release(note);
+        self.sustained.push(note);
+    }
+
+    pub fn release_sustained(&mut self) {
+        self.sustained.clear();
+    }
+
+    /// Marks a note played on the midi input
+    pub fn remote_press(&mut self, note: u8) {
+        self.remote.push(note);
+    }
+
+    pub fn remote_release(&mut self, note: u8) {
+        self.remote.retain(|n| *n != note);
+    }
+
+    pub fn release_remote(&mut self) {
+        self.remote.clear();
+    }
+
+    /// Marks a note of the midi file being played
+    pub fn guide_press(&mut self, note: u8) {
+        self.guide.push(note);
+    }
+
+    pub fn guide_release(&mut self, note: u8) {
+        self.guide.retain(|n| *n != note);
+    }
+
+    pub fn set_guide(&mut self, notes: &[u8]) {
+        self.guide.clear();
+        self.guide.extend_from_slice(notes);
+    }
+
     pub fn release_all(&mut self) {
         self.pressed.clear();
+        self.sustained.clear();
     }
 
-    pub fn base_note(&mut self, note: u8) {
+    pub fn base_note(&mut self, note: i32) {
         self.note = note;
     }
 
-    fn note_pressed(&self, note: u8) -> bool {
-        self.pressed.contains(&(self.note + note))
+    pub fn toggle_labels(&mut self) {
+        self.labels = !self.labels;
+    }
+
+    pub fn set_spelling(&mut self, spelling: Spelling) {
+        self.spelling = spelling;
+        self.labels = true;
+    }
+
+    pub fn toggle_key_labels(&mut self) {
+        self.key_labels = !self.key_labels;
+    }
+
+    /// Names of the computer keys as (row, offset, name)
+    pub fn set_keys(&mut self, keys: Vec<(u8, u8, String)>) {
+        self.keys = keys;
+    }
+
+    /// Deviations of the notes from 12-TET, shown with the note names
+    pub fn set_cents(&mut self, cents: Option<Vec<Option<i32>>>) {
+        self.cents = cents;
+    }
+
+    /// Centers of the hexagons with their rows and offsets from the base note,
+    /// and whether they are white
+    pub fn centers(&self) -> Vec<(f32, f32, u8, u8, bool)> {
+        let r = self.radius;
+        let (x0, y0) = (self.x + r, self.y + self.margin + r);
+        let mut centers = Vec::with_capacity(50);
+
+        for row in 0..4 {
+            let upper = row % 2 == 0;
+            let (count, shift) = if upper { (12, DIAG_X) } else { (13, 0.0) };
+            let cy = y0 + row as f32 * r * DIAG_Y;
+
+            for i in 0..count {
+                let cx = x0 + r * (shift + i as f32 * HORIZ_X);
+                let (offset, white) = if upper { (2 * i + 1, i % 6 > 1) } else { (2 * i, i % 6 < 3) };
+                centers.push((cx, cy, row, offset, white));
+            }
+        }
+
+        centers
+    }
+
+    /// Finds the hexagon under a point, returning its note and the vertical position
+    /// of the point inside it, from 0 at the top to 1 at the bottom
+    pub fn hex_at(&self, x: f32, y: f32) -> Option<(u8, f32)> {
+        let r = self.radius;
+
+        for (cx, cy, _, offset, _) in self.centers() {
+            let (dx, dy) = ((x - cx).abs(), (y - cy).abs());
+
+            // Pointy-top hexagon with the circumradius r
+            if dx <= 0.866 * r && dy <= r - 0.577 * dx {
+                let note = self.note + offset as i32;
+                if !(0..=127).contains(&note) { return None }
+
+                return Some((note as u8, 0.5 + 0.5 * (y - cy) / r))
+            }
+        }
+
+        None
+    }
+
+    /// How the hexagon at an offset from the base note is lit, `None` if it's out of
+    /// the midi range
+    pub fn shade(&self, offset: u8) -> Option<Shade> {
+        let note = self.note + offset as i32;
+        if !(0..=127).contains(&note) { return None }
+        let note = note as u8;
+
+        Some(if self.pressed.contains(&note) {
+            Shade::Pressed
+        } else if self.remote.contains(&note) {
+            Shade::Remote
+        } else if self.guide.contains(&note) {
+            Shade::Guide
+        } else if self.sustained.contains(&note) {
+            Shade::Sustained
+        } else {
+            Shade::Normal
+        })
+    }
+
+    /// Lines written over a hexagon: the note name, the deviation of the tuning and the key
+    pub fn label(&self, row: u8, offset: u8) -> Vec<String> {
+        let note = self.note + offset as i32;
+        let mut lines = vec![];
+        if !(0..=127).contains(&note) { return lines }
+
+        if self.labels {
+            lines.push(self.spelling.name(note as u8));
+
+            let cents = self.cents.as_ref().map(|c| c[note as usize]);
+            match cents {
+                Some(Some(c)) => lines.push(format!("{:+}", c)),
+                Some(None) => lines.push("x".to_string()),
+                None => (),
+            }
+        }
+        if self.key_labels {
+            let key = self.keys.iter().find(|&&(kr, ko, _)| kr == row && ko == offset);
+            if let Some((_, _, k)) = key {
+                lines.push(k.clone())
+            }
+        }
+
+        lines
+    }
+}
+
+#[cfg(feature = "gui")]
+impl Hexes {
+    fn color(&self, white: bool, offset: u8) -> Color {
+        match (white, self.shade(offset)) {
+            (_, None) => Color::new_rgb(0x26,0x32,0x38),
+            (false, Some(Shade::Pressed)) => Color::new_rgb(0x37,0x47,0x4F),
+            (true, Some(Shade::Pressed)) => Color::new_rgb(0x78,0x90,0x9C),
+            (false, Some(Shade::Remote)) => Color::new_rgb(0x00,0x69,0x5C),
+            (true, Some(Shade::Remote)) => Color::new_rgb(0x80,0xCB,0xC4),
+            (false, Some(Shade::Guide)) => Color::new_rgb(0x45,0x27,0xA0),
+            (true, Some(Shade::Guide)) => Color::new_rgb(0xB3,0x9D,0xDB),
+            (false, Some(Shade::Sustained)) => Color::new_rgb(0x6D,0x4C,0x41),
+            (true, Some(Shade::Sustained)) => Color::new_rgb(0xBC,0xAA,0xA4),
+            (false, Some(Shade::Normal)) => Color::new_rgb(0,0,0),
+            (true, Some(Shade::Normal)) => Color::new_rgb(0xFF,0xFF,0xFF),
+        }
+    }
+}
+
+/// Note name in scientific pitch notation, middle C is C4
+pub fn note_name(note: u8) -> String {
+    format!("{}{}", Spelling::Sharps.name(note), note as i32 / 12 - 1)
+}
+
+/// Note names and computer keys written over the hexagons
+#[cfg(feature = "gui")]
+pub struct Labels<'a> {
+    hexes: &'a Hexes,
+    font: &'a Font,
+}
+
+#[cfg(feature = "gui")]
+impl<'a> Labels<'a> {
+    pub fn new(hexes: &'a Hexes, font: &'a Font) -> Self {
+        Labels {
+            hexes: hexes,
+            font: font,
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl<'a> Drawable for Labels<'a> {
+    fn draw<RT: RenderTarget>(&self, target: &mut RT, rs: &mut RenderStates) {
+        let hexes = self.hexes;
+        if !hexes.labels && !hexes.key_labels { return }
+
+        let r = hexes.radius;
+        let size = (0.45 * r) as u32;
+        let mut text = Text::new_init("", self.font, size).unwrap();
+
+        for (cx, cy, row, offset, white) in hexes.centers() {
+            let note = hexes.note + offset as i32;
+            if !(0..=127).contains(&note) { continue }
+
+            let color = if white { Color::new_rgb(0x26,0x32,0x38) } else { Color::new_rgb(0xCF,0xD8,0xDC) };
+            text.set_color(&color);
+
+            let lines = hexes.label(row, offset);
+
+            let count = lines.len();
+            for (i, line) in lines.into_iter().enumerate() {
+                let shift = 0.5 * (i as f32 - 0.5 * (count as f32 - 1.0));
+
+                text.set_string(&line);
+                let bounds = text.get_local_bounds();
+                text.set_origin2f(bounds.left + 0.5 * bounds.width, bounds.top + 0.5 * bounds.height);
+                text.set_position2f(cx, cy + shift * r);
+                text.draw(target, rs);
+            }
+        }
+    }
+}
+
+/// One of the strips next to the hexagons
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wheel {
+    Bend,
+    Modulation,
+}
+
+/// Pitch bend and modulation strips, drawn side by side
+#[derive(Debug)]
+pub struct Wheels {
+    bend: i32,
+    modulation: u8,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+impl Wheels {
+    pub fn new() -> Self {
+        Wheels {
+            bend: 8192,
+            modulation: 0,
+            x: 0.0,
+            y: 0.0,
+            width: 0.0,
+            height: 0.0,
+        }
+    }
+
+    /// Places both strips in the given rectangle
+    pub fn resize(&mut self, x: f32, y: f32, width: f32, height: f32) {
+        self.x = x;
+        self.y = y;
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn bend(&self) -> i32 {
+        self.bend
+    }
+
+    pub fn modulation(&self) -> u8 {
+        self.modulation
+    }
+
+    pub fn set_bend(&mut self, value: i32) {
+        self.bend = value.clamp(0, 16383);
+    }
+
+    pub fn set_modulation(&mut self, value: u8) {
+        self.modulation = value.min(127);
+    }
+
+    /// Finds the strip under a point
+    pub fn at(&self, x: f32, y: f32) -> Option<Wheel> {
+        if y < self.y || y > self.y + self.height || x < self.x || x > self.x + self.width {
+            return None
+        }
+
+        if x < self.x + 0.5 * self.width { Some(Wheel::Bend) } else { Some(Wheel::Modulation) }
+    }
+
+    /// A point on a strip at a height from 0 at the bottom to 1 at the top
+    pub fn point(&self, wheel: Wheel, pos: f32) -> (f32, f32) {
+        let x = self.x + if wheel == Wheel::Bend { 0.25 } else { 0.75 } * self.width;
+        (x, self.y + (1.0 - pos.clamp(0.0, 1.0)) * self.height)
+    }
+
+    /// Sets the value of a strip from the height of a point on it
+    pub fn drag(&mut self, wheel: Wheel, y: f32) {
+        let pos = 1.0 - ((y - self.y) / self.height).clamp(0.0, 1.0);
+        match wheel {
+            Wheel::Bend => self.set_bend((pos * 16383.0).round() as i32),
+            Wheel::Modulation => self.set_modulation((pos * 127.0).round() as u8),
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl Drawable for Wheels {
+    fn draw<RT: RenderTarget>(&self, target: &mut RT, rs: &mut RenderStates) {
+        let w = 0.4 * self.width;
+        let mut rect = RectangleShape::new().unwrap();
+        let mut bar = RectangleShape::new().unwrap();
+        rect.set_fill_color(&Color::new_rgb(0x26,0x32,0x38));
+        bar.set_fill_color(&Color::new_rgb(0x78,0x90,0x9C));
+
+        // Bend grows from the center, modulation from the bottom
+        let bend = self.height * (self.bend - 8192) as f32 / 16383.0;
+        let modulation = self.height * self.modulation as f32 / 127.0;
+        let strips = [
+            (self.x, self.y + 0.5 * self.height - bend.max(0.0), bend.abs()),
+            (self.x + self.width - w, self.y + self.height - modulation, modulation),
+        ];
+
+        for &(x, y, h) in strips.iter() {
+            rect.set_size2f(w, self.height);
+            rect.set_position2f(x, self.y);
+            rect.draw(target, rs);
+
+            bar.set_size2f(w, h.max(2.0));
+            bar.set_position2f(x, y.min(self.y + self.height - 2.0));
+            bar.draw(target, rs);
+        }
     }
 }
 
+#[cfg(feature = "gui")]
 impl Drawable for Hexes {
     fn draw<RT: RenderTarget>(&self, target: &mut RT, rs: &mut RenderStates) {
         let r = self.radius;
@@ -84,12 +483,7 @@ impl Drawable for Hexes {
 
         for _ in 0..2 {
             for i in 0..12 {
-                let color = match (i % 6 > 1, self.note_pressed(2 * i + 1)) {
-                    (false, false) => Color::new_rgb(0,0,0),
-                    (true, false) => Color::new_rgb(0xFF,0xFF,0xFF),
-                    (false, true) => Color::new_rgb(0x37,0x47,0x4F),
-                    (true, true) => Color::new_rgb(0x78,0x90,0x9C),
-                };
+                let color = self.color(i % 6 > 1, 2 * i + 1);
 
                 cs.set_fill_color(&color);
 
@@ -100,12 +494,7 @@ impl Drawable for Hexes {
             cs.move2f(r * (-12.0 * HORIZ_X - DIAG_X), r * DIAG_Y);
 
             for i in 0..13 {
-                let color = match (i % 6 < 3, self.note_pressed(2 * i)) {
-                    (false, false) => Color::new_rgb(0,0,0),
-                    (true, false) => Color::new_rgb(0xFF,0xFF,0xFF),
-                    (false, true) => Color::new_rgb(0x37,0x47,0x4F),
-                    (true, true) => Color::new_rgb(0x78,0x90,0x9C),
-                };
+                let color = self.color(i % 6 < 3, 2 * i);
 
                 cs.set_fill_color(&color);
 
@@ -0,0 +1,135 @@
+use std::fmt;
+
+use random::Random;
+
+const STEP: i32 = 8;
+
+/// The way note velocities are derived from the current value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    Fixed,
+    /// Random deviation within the given range, to make playing less mechanical
+    Human(u8),
+    /// Velocity offsets for each keyboard row, from the top one
+    Accent([i8; 4]),
+}
+
+#[derive(Debug)]
+pub struct Velocity {
+    value: u8,
+    curve: Curve,
+}
+
+impl Velocity {
+    pub fn new() -> Self {
+        Velocity {
+            value: 64,
+            curve: Curve::Fixed,
+        }
+    }
+
+    pub fn set(&mut self, value: u8) {
+        self.value = clamp(value as i32);
+    }
+
+    pub fn set_curve(&mut self, curve: Curve) {
+        self.curve = curve;
+    }
+
+    pub fn value(&self) -> u8 {
+        self.value
+    }
+
+    pub fn curve(&self) -> &Curve {
+        &self.curve
+    }
+
+    pub fn up(&mut self) {
+        self.value = clamp(self.value as i32 + STEP);
+    }
+
+    pub fn down(&mut self) {
+        self.value = clamp(self.value as i32 - STEP);
+    }
+
+    /// Velocity for a note played from the given row, humanized with `random`
+    pub fn note(&self, row: u8, random: &mut Random) -> u8 {
+        let value = self.value as i32;
+
+        match self.curve {
+            Curve::Fixed => self.value,
+            Curve::Human(range) => {
+                let range = range as i32;
+                let dev = (random.next_u32() % (2 * range as u32 + 1)) as i32 - range;
+                clamp(value + dev)
+            },
+            Curve::Accent(ref accents) => {
+                let accent = accents.get(row as usize).cloned().unwrap_or(0);
+                clamp(value + accent as i32)
+            },
+        }
+    }
+}
+
+impl fmt::Display for Velocity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.curve {
+            Curve::Fixed => write!(f, "velocity {}", self.value),
+            Curve::Human(range) => write!(f, "velocity {}±{}", self.value, range),
+            Curve::Accent(_) => write!(f, "velocity {} accented", self.value),
+        }
+    }
+}
+
+// Velocity 0 is a note off, so it's never produced
+fn clamp(value: i32) -> u8 {
+    if value < 1 { 1 } else if value > 127 { 127 } else { value as u8 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed() {
+        let mut velocity = Velocity::new();
+        let mut random = Random::new();
+        assert_eq!(velocity.note(3, &mut random), 64);
+
+        velocity.set(0);
+        assert_eq!(velocity.value(), 1);
+        velocity.set(200);
+        assert_eq!(velocity.value(), 127);
+        velocity.up();
+        assert_eq!(velocity.value(), 127);
+        velocity.down();
+        assert_eq!(velocity.note(0, &mut random), 119);
+    }
+
+    #[test]
+    fn human() {
+        let mut velocity = Velocity::new();
+        velocity.set_curve(Curve::Human(10));
+        let mut random = Random::new();
+
+        let notes: Vec<u8> = (0..200).map(|_| velocity.note(0, &mut random)).collect();
+        assert!(notes.iter().all(|v| (54..=74).contains(v)));
+        assert!(notes.iter().any(|&v| v != notes[0]));
+
+        // Never a note off
+        velocity.set(1);
+        assert!((0..200).all(|_| velocity.note(0, &mut random) >= 1));
+    }
+
+    #[test]
+    fn accent() {
+        let mut velocity = Velocity::new();
+        velocity.set(120);
+        velocity.set_curve(Curve::Accent([20, 5, 0, -30]));
+        let mut random = Random::new();
+
+        let rows: Vec<u8> = (0..5).map(|r| velocity.note(r, &mut random)).collect();
+        assert_eq!(rows, vec![127, 125, 120, 90, 120]);
+        assert_eq!(velocity.to_string(), "velocity 120 accented");
+    }
+}
@@ -0,0 +1,36 @@
+/// Gives every note its own channel from a range, so notes can be bent separately
+#[derive(Debug)]
+pub struct Voices {
+    lo: u8,
+    hi: u8,
+    clock: u64,
+    used: [u64; 16],
+}
+
+impl Voices {
+    pub fn new(lo: u8, hi: u8) -> Self {
+        Voices {
+            lo: lo,
+            hi: hi,
+            clock: 0,
+            used: [0; 16],
+        }
+    }
+
+    pub fn range(&self) -> (u8, u8) {
+        (self.lo, self.hi)
+    }
+
+    /// Picks the least recently used channel that isn't busy. If every channel has
+    /// a note, the least recently used one is shared.
+    pub fn allocate(&mut self, busy: &[u8]) -> u8 {
+        let chans = self.lo..self.hi + 1;
+        let free = chans.clone().filter(|c| !busy.contains(c))
+            .min_by_key(|&c| self.used[c as usize]);
+        let chan = free.or_else(|| chans.min_by_key(|&c| self.used[c as usize])).unwrap_or(self.lo);
+
+        self.clock += 1;
+        self.used[chan as usize] = self.clock;
+        chan
+    }
+}